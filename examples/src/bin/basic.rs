@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use engineio::{Client, EventHandler, PacketData};
+use engineio::{Client, DisconnectReason, EventHandler, PacketData};
 
 #[async_std::main]
 async fn main() {
@@ -20,8 +20,8 @@ impl EventHandler for Handler {
         println!("connect");
     }
 
-    async fn on_disconnect(&mut self) {
-        println!("disconnect");
+    async fn on_disconnect(&mut self, reason: DisconnectReason) {
+        println!("disconnect: {:?}", reason);
     }
 
     async fn on_message(&mut self, data: PacketData) {
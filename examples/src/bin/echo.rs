@@ -3,7 +3,7 @@ use async_std::prelude::*;
 use async_trait::async_trait;
 use futures::try_join;
 
-use engineio::{Client, EventHandler, PacketData, Sender};
+use engineio::{Client, DisconnectReason, EventHandler, PacketData, Sender};
 use std::error::Error;
 
 #[async_std::main]
@@ -35,7 +35,7 @@ async fn emit_loop(mut sender: Sender) -> Result<(), Box<dyn Error>> {
 
     while let Some(line) = lines.next().await {
         let line = line?;
-        sender.emit_str(line).await;
+        sender.emit_str(line).await?;
     }
     Ok(())
 }
@@ -48,8 +48,8 @@ impl EventHandler for EngineIOHandler {
         println!("connect");
     }
 
-    async fn on_disconnect(&mut self) {
-        println!("disconnect");
+    async fn on_disconnect(&mut self, reason: DisconnectReason) {
+        println!("disconnect: {:?}", reason);
     }
 
     async fn on_message(&mut self, data: PacketData) {
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use engineio::Payload;
+
+// Decoding the body of a polling response must never panic, whatever the server sends.
+fuzz_target!(|data: &[u8]| {
+    let _ = Payload::new(data);
+    let _ = Payload::new_v4(data);
+});
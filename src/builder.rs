@@ -0,0 +1,445 @@
+use crate::client::{Client, EventHandler, Messages, Sender};
+use crate::error::EIOError;
+use crate::reconnect::ReconnectPolicy;
+use async_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use futures::future::BoxFuture;
+use surf::http::header::{HeaderName as PollingHeaderName, HeaderValue as PollingHeaderValue};
+use surf::middleware::{HttpClient, Middleware, Next, Request, Response};
+use surf::url::Url;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// A transport over which packets are exchanged with the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// Long-polling via HTTP GET and POST requests.
+    Polling,
+    /// A single websocket connection.
+    Websocket,
+}
+
+impl Transport {
+    fn name(self) -> &'static str {
+        match self {
+            Transport::Polling => "polling",
+            Transport::Websocket => "websocket",
+        }
+    }
+}
+
+/// The version of the engine.io protocol spoken with the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolVersion {
+    V3,
+    /// Separates payload packets by `\x1e` and has the server send pings.
+    V4,
+}
+
+impl ProtocolVersion {
+    fn query_value(self) -> &'static str {
+        match self {
+            ProtocolVersion::V3 => "3",
+            ProtocolVersion::V4 => "4",
+        }
+    }
+}
+
+/// How the client encodes the body of polling requests in protocol version 3.
+/// In version 4, payloads are always text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadEncoding {
+    /// Packets are framed as bytes (XHR2), binary data is sent as is.
+    Binary,
+    /// Packets are framed as `text/plain`, binary data is base64 encoded,
+    /// for servers and proxies that do not support binary bodies.
+    Text,
+}
+
+/// The validated options of a client, from which all
+/// request urls of a connection are built.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientOptions {
+    url: Url,
+    query: Vec<(String, String)>,
+    /// The extra headers of the websocket handshake.
+    pub(crate) headers: Vec<(HeaderName, HeaderValue)>,
+    /// The same headers for surf, which uses an older version of `http`.
+    polling_headers: PollingHeaders,
+    pub(crate) transports: Vec<Transport>,
+    timestamp_param: String,
+    force_base64: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) reconnect_policy: ReconnectPolicy,
+    pub(crate) protocol_version: ProtocolVersion,
+    pub(crate) payload_encoding: PayloadEncoding,
+    pub(crate) queue_capacity: usize,
+    pub(crate) max_payload: Option<usize>,
+}
+
+impl ClientOptions {
+    /// The url for a request over `transport`, which includes the
+    /// session id once the handshake is done.
+    pub(crate) fn url(&self, transport: Transport, sid: Option<&str>) -> String {
+        let mut url = self.url.clone();
+        {
+            let mut query = url.query_pairs_mut();
+            query.extend_pairs(self.query.iter());
+            query.append_pair("EIO", self.protocol_version.query_value());
+            query.append_pair("transport", transport.name());
+            if self.force_base64 {
+                query.append_pair("b64", "1");
+            }
+            if let Some(sid) = sid {
+                query.append_pair("sid", sid);
+            }
+            query.append_pair(&self.timestamp_param, &timestamp());
+        }
+        url.into()
+    }
+
+    /// Add the configured extra headers to an HTTP request.
+    pub(crate) fn with_headers<C: HttpClient>(
+        &self,
+        request: surf::Request<C>,
+    ) -> surf::Request<C> {
+        if self.polling_headers.0.is_empty() {
+            request
+        } else {
+            request.middleware(self.polling_headers.clone())
+        }
+    }
+}
+
+/// The extra headers of polling requests, which are added by a
+/// middleware since surf only takes `&'static str` header names.
+#[derive(Debug, Clone)]
+struct PollingHeaders(Arc<Vec<(PollingHeaderName, PollingHeaderValue)>>);
+
+impl<C: HttpClient> Middleware<C> for PollingHeaders {
+    fn handle<'a>(
+        &'a self,
+        mut req: Request,
+        client: C,
+        next: Next<'a, C>,
+    ) -> BoxFuture<'a, Result<Response, surf::Exception>> {
+        for (name, value) in self.0.iter() {
+            req.headers_mut().insert(name.clone(), value.clone());
+        }
+        next.run(req, client)
+    }
+}
+
+fn timestamp() -> String {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    format!("{}.{}", time.as_secs(), time.subsec_nanos())
+}
+
+/// A builder to configure a [`Client`](struct.Client.html) before connecting.
+///
+/// ```no_run
+/// # use engineio::{ClientBuilder, DisconnectReason, EventHandler, PacketData, Transport};
+/// # struct Handler;
+/// # #[async_trait::async_trait]
+/// # impl EventHandler for Handler {
+/// #     async fn on_connect(&mut self) {}
+/// #     async fn on_disconnect(&mut self, _reason: DisconnectReason) {}
+/// #     async fn on_message(&mut self, _data: PacketData) {}
+/// # }
+/// # async fn connect() -> Result<(), engineio::EIOError> {
+/// let client = ClientBuilder::new("http://localhost:8080")
+///     .path("/engine.io/")
+///     .query("token", "secret")
+///     .header("User-Agent", "engineio")
+///     .transports(&[Transport::Websocket])
+///     .connect(Handler)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ClientBuilder {
+    url: String,
+    path: Option<String>,
+    /// The path used if neither `url` nor [`path`](#method.path) has one.
+    default_path: &'static str,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    transports: Vec<Transport>,
+    timestamp_param: String,
+    force_base64: bool,
+    connect_timeout: Option<Duration>,
+    reconnect_policy: ReconnectPolicy,
+    protocol_version: ProtocolVersion,
+    payload_encoding: PayloadEncoding,
+    queue_capacity: usize,
+    max_payload: Option<usize>,
+}
+
+impl ClientBuilder {
+    /// Create a builder for a connection to the server at `url`.
+    /// Unless a path is configured, the path of `url` is used,
+    /// which defaults to `/engine.io/`.
+    pub fn new(url: &str) -> Self {
+        ClientBuilder {
+            url: url.to_owned(),
+            path: None,
+            default_path: "/engine.io/",
+            query: Vec::new(),
+            headers: Vec::new(),
+            transports: vec![Transport::Polling, Transport::Websocket],
+            timestamp_param: "t".into(),
+            force_base64: false,
+            connect_timeout: None,
+            reconnect_policy: ReconnectPolicy::disabled(),
+            protocol_version: ProtocolVersion::V3,
+            payload_encoding: PayloadEncoding::Binary,
+            queue_capacity: 1024,
+            max_payload: None,
+        }
+    }
+
+    /// The path under which the server is reachable.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    /// The path used instead of `/engine.io/` if neither
+    /// the url nor [`path`](#method.path) has one.
+    pub(crate) fn default_path(mut self, path: &'static str) -> Self {
+        self.default_path = path;
+        self
+    }
+
+    /// Add a query parameter to every request.
+    pub fn query(mut self, key: &str, value: &str) -> Self {
+        self.query.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Add an HTTP header to every request, including the websocket handshake.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// The transports the client may use, by default polling and websocket.
+    /// The session is established with the first one, and upgraded
+    /// to the websocket if it is allowed and the server offers it.
+    pub fn transports(mut self, transports: &[Transport]) -> Self {
+        self.transports = transports.to_vec();
+        self
+    }
+
+    /// The name of the query parameter that carries a timestamp
+    /// to prevent caching, by default `t`.
+    pub fn timestamp_param(mut self, name: &str) -> Self {
+        self.timestamp_param = name.to_owned();
+        self
+    }
+
+    /// Ask the server to send binary data as base64 encoded text (`b64=1`)
+    /// when polling, for proxies that mangle binary response bodies.
+    pub fn force_base64(mut self, enabled: bool) -> Self {
+        self.force_base64 = enabled;
+        self
+    }
+
+    /// The time after which an unfinished handshake is aborted.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// How to reconnect when the connection is lost, by default not at all.
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// The version of the engine.io protocol, by default version 3.
+    pub fn protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// How the body of polling requests is encoded in protocol version 3,
+    /// by default as bytes. Combine `PayloadEncoding::Text` with
+    /// [`force_base64`](#method.force_base64) to only exchange text with the server.
+    pub fn payload_encoding(mut self, encoding: PayloadEncoding) -> Self {
+        self.payload_encoding = encoding;
+        self
+    }
+
+    /// The number of packets that may wait to be written to the server,
+    /// by default 1024. Once the queue is full, `emit` waits for capacity
+    /// and `try_emit` fails. Every sender may queue one extra packet.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// The maximum size in bytes of the payload of a polling request, into which
+    /// all queued packets are batched. By default the limit announced by the
+    /// server is used, or 1 MB if there is none. A single packet that exceeds
+    /// the limit is sent on its own.
+    pub fn max_payload(mut self, bytes: usize) -> Self {
+        self.max_payload = Some(bytes);
+        self
+    }
+
+    /// Connect to the server with the configured options.
+    pub async fn connect(
+        self,
+        event_handler: impl EventHandler + Send + Sync + 'static,
+    ) -> Result<Client, EIOError> {
+        Client::open(self.build()?, event_handler).await
+    }
+
+    /// Connect to the server with the configured options, and receive
+    /// the messages from a stream instead of an event handler.
+    pub async fn connect_stream(self) -> Result<(Sender, Messages), EIOError> {
+        Client::open_stream(self.build()?).await
+    }
+
+    pub(crate) fn build(self) -> Result<ClientOptions, EIOError> {
+        let mut url = Url::parse(&self.url).map_err(|err| {
+            EIOError::InvalidOptions(format!("Invalid url {}: {}", self.url, err))
+        })?;
+
+        match self.path {
+            Some(path) => url.set_path(&path),
+            None if url.path() == "/" => url.set_path(self.default_path),
+            None => (),
+        }
+
+        let mut headers = Vec::with_capacity(self.headers.len());
+        let mut polling_headers = Vec::with_capacity(self.headers.len());
+        for (name, value) in self.headers.iter() {
+            let invalid = |err: &dyn std::error::Error| {
+                EIOError::InvalidOptions(format!("Invalid header {}: {}", name, err))
+            };
+            headers.push((
+                HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid(&err))?,
+                HeaderValue::from_str(value).map_err(|err| invalid(&err))?,
+            ));
+            polling_headers.push((
+                PollingHeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid(&err))?,
+                PollingHeaderValue::from_str(value).map_err(|err| invalid(&err))?,
+            ));
+        }
+
+        if self.transports.is_empty() {
+            return Err(EIOError::InvalidOptions("No transport allowed".into()));
+        }
+
+        if self.queue_capacity == 0 {
+            return Err(EIOError::InvalidOptions(
+                "Queue capacity must not be 0".into(),
+            ));
+        }
+
+        Ok(ClientOptions {
+            url,
+            query: self.query,
+            headers,
+            polling_headers: PollingHeaders(Arc::new(polling_headers)),
+            transports: self.transports,
+            timestamp_param: self.timestamp_param,
+            force_base64: self.force_base64,
+            connect_timeout: self.connect_timeout,
+            reconnect_policy: self.reconnect_policy,
+            protocol_version: self.protocol_version,
+            payload_encoding: self.payload_encoding,
+            queue_capacity: self.queue_capacity,
+            max_payload: self.max_payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_of(url: &str) -> Vec<(String, String)> {
+        Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .filter(|(key, _)| key != "ts")
+            .collect()
+    }
+
+    #[test]
+    fn test_url_from_options() {
+        let options = ClientBuilder::new("http://localhost:8080/?app=chat")
+            .query("token", "a b")
+            .timestamp_param("ts")
+            .build()
+            .unwrap();
+
+        let url = options.url(Transport::Polling, None);
+        assert!(url.starts_with("http://localhost:8080/engine.io/?"));
+        assert_eq!(
+            query_of(&url),
+            vec![
+                ("app".into(), "chat".into()),
+                ("token".into(), "a b".into()),
+                ("EIO".into(), "3".into()),
+                ("transport".into(), "polling".into()),
+            ]
+        );
+
+        let url = options.url(Transport::Websocket, Some("abc"));
+        assert_eq!(query_of(&url)[3], ("transport".into(), "websocket".into()));
+        assert_eq!(query_of(&url)[4], ("sid".into(), "abc".into()));
+    }
+
+    #[test]
+    fn test_force_base64_query() {
+        let options = ClientBuilder::new("http://localhost")
+            .force_base64(true)
+            .build()
+            .unwrap();
+        let url = options.url(Transport::Polling, Some("abc"));
+
+        assert!(url.contains("&transport=polling&b64=1&sid=abc&"));
+    }
+
+    #[test]
+    fn test_path_of_url_is_kept_unless_configured() {
+        let options = ClientBuilder::new("http://localhost/socket/")
+            .build()
+            .unwrap();
+        assert!(options
+            .url(Transport::Polling, None)
+            .starts_with("http://localhost/socket/?"));
+
+        let options = ClientBuilder::new("http://localhost/socket/")
+            .path("/eio")
+            .build()
+            .unwrap();
+        assert!(options
+            .url(Transport::Polling, None)
+            .starts_with("http://localhost/eio?"));
+    }
+
+    #[test]
+    fn test_invalid_options_are_rejected() {
+        assert!(ClientBuilder::new("not a url").build().is_err());
+        assert!(ClientBuilder::new("http://localhost")
+            .header("X-Token", "line\nbreak")
+            .build()
+            .is_err());
+        assert!(ClientBuilder::new("http://localhost")
+            .transports(&[])
+            .build()
+            .is_err());
+        assert!(ClientBuilder::new("http://localhost")
+            .queue_capacity(0)
+            .build()
+            .is_err());
+    }
+}
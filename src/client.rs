@@ -1,226 +1,761 @@
-use crate::packet::{Packet, PacketData, PacketType};
-use crate::payload::{Payload, PayloadDecodeError};
+use crate::builder::{ClientBuilder, ClientOptions, PayloadEncoding, ProtocolVersion, Transport};
+use crate::error::EIOError;
+use crate::packet::{OpenPacket, Packet, PacketData, PacketType};
+use crate::payload::Payload;
+use crate::queue::{self, QueueReceiver, QueueSender, Queued};
+use crate::websocket::{self, WebSocket};
 use async_std::task::{self, JoinHandle};
 use async_std::task_local;
 use async_trait::async_trait;
-use futures::channel::mpsc;
-use futures::sink::SinkExt;
-use futures::stream::StreamExt;
-use futures::try_join;
-use serde::{Deserialize, Serialize};
+use async_tungstenite::tungstenite::Message;
+use futures::channel::{mpsc, oneshot};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{FusedStream, SplitSink, SplitStream, Stream, StreamExt};
+use futures::task::{Context, Poll};
+use futures::{pin_mut, ready, select, try_join, FutureExt};
 
 use std::cell::Cell;
-use std::fmt;
-use std::time::SystemTime;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use log::{debug, error, info};
 
 #[async_trait]
 pub trait EventHandler {
+    /// Called when a session is open. Packets are already being exchanged,
+    /// so the handler can emit over its sender without blocking the connection.
     async fn on_connect(&mut self);
 
-    async fn on_disconnect(&mut self);
+    /// Called exactly once when a session ends, with the reason it ended.
+    async fn on_disconnect(&mut self, reason: DisconnectReason);
 
     async fn on_message(&mut self, data: PacketData);
+
+    /// Called before the given attempt to reconnect, counting from 1.
+    async fn on_reconnecting(&mut self, _attempt: u32) {}
+
+    /// Called once a new session was established after a reconnect.
+    async fn on_reconnected(&mut self) {}
+}
+
+/// The reason a session ended, as passed to
+/// [`on_disconnect`](trait.EventHandler.html#tymethod.on_disconnect).
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    /// The server closed the session.
+    ServerClose,
+    /// The client closed the session with [`close`](struct.Client.html#method.close).
+    ClientClose,
+    /// A ping or pong was not received in time.
+    PingTimeout,
+    /// The transport failed, e.g. because the connection was lost.
+    TransportError(EIOError),
+    /// The server sent a packet that could not be decoded.
+    ParseError(EIOError),
+}
+
+impl From<EIOError> for DisconnectReason {
+    fn from(err: EIOError) -> Self {
+        match err {
+            EIOError::PingNotReceived | EIOError::PongNotReceived => DisconnectReason::PingTimeout,
+            EIOError::Decode(_) | EIOError::Protocol(_) => DisconnectReason::ParseError(err),
+            _ => DisconnectReason::TransportError(err),
+        }
+    }
 }
 
 /// A struct generated by the [`sender`](struct.Client.html#method.sender) method
 /// on the client, which can be used to emit messages to the engine.io server
 /// that client is connected to.
+///
+/// Messages wait in a bounded queue until they are written, see
+/// [`queue_capacity`](struct.ClientBuilder.html#method.queue_capacity).
+/// When polling, the messages queued while the previous request was in flight
+/// are sent with a single request of at most
+/// [`max_payload`](struct.ClientBuilder.html#method.max_payload) bytes.
+/// A failed write closes the connection, after which emits fail with
+/// `EIOError::Closed`.
+#[derive(Clone)]
 pub struct Sender {
-    write_channel: mpsc::UnboundedSender<Packet>,
+    write_channel: QueueSender,
+}
+
+/// The messages of a connection, as returned by
+/// [`ClientBuilder::connect_stream`](struct.ClientBuilder.html#method.connect_stream).
+///
+/// The stream ends when the connection is closed,
+/// after yielding the error that closed it, if any.
+/// Dropping it closes the connection.
+pub struct Messages {
+    receiver: mpsc::UnboundedReceiver<PacketData>,
+    client: Client,
+    /// Set once the result of the connection was yielded.
+    finished: bool,
+}
+
+/// A future returned by [`emit_confirmed`](struct.Sender.html#method.emit_confirmed),
+/// which resolves once the message was written to the transport, i.e. the
+/// server accepted the polling request or the websocket message was sent.
+///
+/// It fails with the error of the transport if the write failed,
+/// or if the connection was closed before the message was written.
+pub struct Delivery {
+    receiver: oneshot::Receiver<Result<(), EIOError>>,
+}
+
+impl Future for Delivery {
+    type Output = Result<(), EIOError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_unpin(cx).map(|result| {
+            // The connection was closed before the message was written
+            result.unwrap_or(Err(EIOError::Closed))
+        })
+    }
 }
 
 pub struct Client {
-    write_channel: mpsc::UnboundedSender<Packet>,
+    sender: Sender,
     join_task_handle: JoinHandle<Result<(), EIOError>>,
 }
 
 struct EngineIO {
     sid: String,
-    base_url: String,
+    options: Arc<ClientOptions>,
+    write_channel: QueueSender,
+    /// Notified about every ping the server sends in protocol version 4.
+    pings: mpsc::UnboundedSender<()>,
     ping_interval: u32,
     ping_timeout: u32,
+    upgrades: Vec<String>,
+    /// Set once the websocket probe succeeded, which pauses polling.
+    upgrading: AtomicBool,
+    /// The maximum size of the payload of a polling request.
+    max_payload: usize,
+    /// Set once the client started to write its close packet.
+    closing: AtomicBool,
+    /// Notified once either side closed the session, which ends it.
+    closed: mpsc::UnboundedSender<DisconnectReason>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[allow(non_snake_case)]
-struct OpenPacket {
-    sid: String,
-    upgrades: Vec<String>,
-    pingInterval: u32,
-    pingTimeout: u32,
+/// The transport over which packets are exchanged with the server.
+enum Connection {
+    Polling,
+    Websocket(Box<WebSocket>),
 }
 
-#[derive(Debug)]
-pub enum EIOError {
-    /// An error with the underlying transport.
-    Transport(String),
-    /// A violation of the engine.io protocol.
-    Protocol(String),
-    /// An error to signal that no pong was
-    /// received from the other end.
-    PongNotReceived,
+impl Sender {
+    pub async fn emit_str(&mut self, data: String) -> Result<(), EIOError> {
+        self.emit(PacketData::Str(data)).await
+    }
+
+    pub async fn emit_bytes(&mut self, data: Vec<u8>) -> Result<(), EIOError> {
+        self.emit(PacketData::Bytes(data)).await
+    }
+
+    /// Queue a message for the server, waiting for capacity if the queue is full.
+    /// Fails with `EIOError::Closed` once the connection is closed.
+    pub async fn emit(&mut self, data: PacketData) -> Result<(), EIOError> {
+        info!("Emitting {:?}", data);
+        self.write_channel
+            .send(Packet::new(PacketType::Message, data))
+            .await
+    }
+
+    /// Queue a message for the server, or fail with
+    /// `EIOError::QueueFull` right away if the queue is full.
+    pub fn try_emit(&mut self, data: PacketData) -> Result<(), EIOError> {
+        self.write_channel
+            .try_send(Packet::new(PacketType::Message, data))
+    }
+
+    /// Queue a message for the server like [`emit`](#method.emit), and return
+    /// a [`Delivery`](struct.Delivery.html) that resolves once it was written.
+    pub async fn emit_confirmed(&mut self, data: PacketData) -> Delivery {
+        info!("Emitting {:?}", data);
+        let receiver = match self
+            .write_channel
+            .send_confirmed(Packet::new(PacketType::Message, data))
+            .await
+        {
+            Ok(receiver) => receiver,
+            Err(err) => {
+                let (delivery, receiver) = oneshot::channel();
+                let _ = delivery.send(Err(err));
+                receiver
+            }
+        };
+        Delivery { receiver }
+    }
+
+    /// The number of packets waiting to be written to the server.
+    pub fn queue_depth(&self) -> usize {
+        self.write_channel.depth()
+    }
+
+    /// Whether the connection was closed, after which emits fail.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.write_channel.is_closed()
+    }
 }
 
-impl std::error::Error for EIOError {}
+/// Emits the data sent into it, like [`emit`](#method.emit).
+impl Sink<PacketData> for Sender {
+    type Error = EIOError;
 
-impl fmt::Display for EIOError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            EIOError::Transport(str_) => write!(f, "{}", str_),
-            EIOError::Protocol(str_) => write!(f, "{}", str_),
-            EIOError::PongNotReceived => write!(f, "Pong was not received"),
-        }
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), EIOError>> {
+        self.write_channel.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, data: PacketData) -> Result<(), EIOError> {
+        self.write_channel
+            .start_send_unpin(Packet::new(PacketType::Message, data))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), EIOError>> {
+        self.write_channel.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), EIOError>> {
+        self.write_channel.poll_close_unpin(cx)
     }
 }
 
-impl From<surf::Exception> for EIOError {
-    fn from(err: surf::Exception) -> Self {
-        Self::Transport(format!("{}", err))
+impl Messages {
+    /// Close the connection gracefully, like [`Client::close`](struct.Client.html#method.close).
+    /// The stream ends once the connection is shut down.
+    pub async fn close(&mut self) -> Result<(), EIOError> {
+        self.client.close().await
     }
 }
 
-impl From<PayloadDecodeError> for EIOError {
-    fn from(err: PayloadDecodeError) -> Self {
-        Self::Protocol(format!("{}", err))
+impl Stream for Messages {
+    type Item = Result<PacketData, EIOError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        if let Some(data) = ready!(self.receiver.poll_next_unpin(cx)) {
+            return Poll::Ready(Some(Ok(data)));
+        }
+
+        // The handler is gone, so the connection is about to finish
+        let result = ready!(self.client.join_task_handle.poll_unpin(cx));
+        self.finished = true;
+        Poll::Ready(result.err().map(Err))
     }
 }
 
-impl Sender {
-    pub async fn emit_str(&mut self, data: String) {
-        self.emit(PacketData::Str(data)).await;
+impl Drop for Messages {
+    fn drop(&mut self) {
+        if !self.client.sender.is_closed() {
+            info!("Closing connection");
+            // The client never emits itself, so its slot in the queue is free
+            let _ = self
+                .client
+                .sender
+                .write_channel
+                .try_send(Packet::with_str(PacketType::Close, ""));
+        }
     }
+}
 
-    async fn emit(&mut self, data: PacketData) {
-        info!("Emitting {:?}", data);
-        self.write_channel
-            .send(Packet::new(PacketType::Message, data))
-            .await
-            .unwrap();
+/// Forwards the messages of a connection to a [`Messages`](struct.Messages.html) stream.
+struct StreamHandler {
+    messages: mpsc::UnboundedSender<PacketData>,
+}
+
+#[async_trait]
+impl EventHandler for StreamHandler {
+    async fn on_connect(&mut self) {}
+
+    async fn on_disconnect(&mut self, _reason: DisconnectReason) {}
+
+    async fn on_message(&mut self, data: PacketData) {
+        // The stream may have been dropped, which closes the connection
+        let _ = self.messages.unbounded_send(data);
     }
 }
 
 impl Client {
+    /// Wait until the connection is closed. Fails with the error that
+    /// closed it, or returns `Ok` once [`close`](#method.close) finished.
     pub async fn join(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         futures::join!(&mut self.join_task_handle).0?;
         Ok(())
     }
 
+    /// Connect to the engine.io server at `url` using the long-polling transport,
+    /// which is upgraded to a websocket if the server offers it.
+    /// Use [`builder`](#method.builder) to configure the connection.
     pub async fn connect(
         url: &str,
         event_handler: impl EventHandler + Send + Sync + 'static,
     ) -> Result<Client, EIOError> {
-        let connect_url = format!("{}?transport=polling&EIO=3", url);
-        info!("Establishing connection to {}", connect_url);
-        let bytes = surf::get(&connect_url).recv_bytes().await.unwrap();
-        let payload = Payload::new(&bytes).unwrap();
-
-        if let PacketData::Str(string) = payload.packets().first().unwrap().data() {
-            let packet: OpenPacket = serde_json::from_str(string).unwrap();
-            debug!("Spawning task, sid is {}", packet.sid);
-            let (sender, receiver) = mpsc::unbounded();
-
-            let join_task_handle = task::spawn(EngineIO::fire_up(
-                packet,
-                url.to_owned(),
-                event_handler,
-                sender.clone(),
-                receiver,
-            ));
-
-            let eio_client = Client {
-                write_channel: sender.clone(),
-                join_task_handle,
-            };
+        Self::builder(url).connect(event_handler).await
+    }
 
-            Ok(eio_client)
-        } else {
-            panic!("expected string");
-        }
+    /// Create a builder to configure a connection to the server at `url`.
+    pub fn builder(url: &str) -> ClientBuilder {
+        ClientBuilder::new(url)
     }
 
-    pub async fn emit_str(&mut self, data: String) {
-        self.emit(PacketData::Str(data)).await;
+    pub(crate) async fn open_stream(
+        options: ClientOptions,
+    ) -> Result<(Sender, Messages), EIOError> {
+        let (messages, receiver) = mpsc::unbounded();
+        let mut client = Self::open(options, StreamHandler { messages }).await?;
+
+        let sender = client.sender();
+        let messages = Messages {
+            receiver,
+            client,
+            finished: false,
+        };
+        Ok((sender, messages))
     }
 
-    async fn emit(&mut self, data: PacketData) {
-        info!("Emitting {:?}", data);
-        self.write_channel
-            .send(Packet::new(PacketType::Message, data))
-            .await
-            .unwrap();
+    pub(crate) async fn open(
+        options: ClientOptions,
+        event_handler: impl EventHandler + Send + Sync + 'static,
+    ) -> Result<Client, EIOError> {
+        Self::open_with(options, |_| event_handler).await
+    }
+
+    /// Open a connection whose event handler is created with a sender
+    /// of that connection, so that it can emit packets itself.
+    pub(crate) async fn open_with<H>(
+        options: ClientOptions,
+        new_handler: impl FnOnce(Sender) -> H,
+    ) -> Result<Client, EIOError>
+    where
+        H: EventHandler + Send + Sync + 'static,
+    {
+        let options = Arc::new(options);
+        let (packet, connection) = EngineIO::handshake(&options).await?;
+        debug!("Spawning task, sid is {}", packet.sid);
+        let (sender, receiver) = queue::queue(options.queue_capacity);
+        let event_handler = new_handler(Sender {
+            write_channel: sender.clone(),
+        });
+
+        let join_task_handle = task::spawn(EngineIO::run(
+            packet,
+            options,
+            connection,
+            event_handler,
+            sender.clone(),
+            receiver,
+        ));
+
+        Ok(Client {
+            sender: Sender {
+                write_channel: sender,
+            },
+            join_task_handle,
+        })
+    }
+
+    /// Emit a text message, like [`Sender::emit_str`](struct.Sender.html#method.emit_str).
+    pub async fn emit_str(&mut self, data: String) -> Result<(), EIOError> {
+        self.sender.emit_str(data).await
+    }
+
+    /// Emit a binary message, like [`Sender::emit_bytes`](struct.Sender.html#method.emit_bytes).
+    pub async fn emit_bytes(&mut self, data: Vec<u8>) -> Result<(), EIOError> {
+        self.sender.emit_bytes(data).await
+    }
+
+    /// Emit a message, like [`Sender::emit`](struct.Sender.html#method.emit).
+    pub async fn emit(&mut self, data: PacketData) -> Result<(), EIOError> {
+        self.sender.emit(data).await
+    }
+
+    /// Emit a message without waiting, like [`Sender::try_emit`](struct.Sender.html#method.try_emit).
+    pub fn try_emit(&mut self, data: PacketData) -> Result<(), EIOError> {
+        self.sender.try_emit(data)
+    }
+
+    /// Emit a message and confirm its delivery, like
+    /// [`Sender::emit_confirmed`](struct.Sender.html#method.emit_confirmed).
+    pub async fn emit_confirmed(&mut self, data: PacketData) -> Delivery {
+        self.sender.emit_confirmed(data).await
+    }
+
+    /// The number of packets waiting to be written to the server.
+    pub fn queue_depth(&self) -> usize {
+        self.sender.queue_depth()
+    }
+
+    /// Close the connection gracefully. The messages queued so far are written,
+    /// followed by a close packet, after which the connection is shut down,
+    /// `on_disconnect` is called and [`join`](#method.join) returns `Ok`.
+    pub async fn close(&mut self) -> Result<(), EIOError> {
+        info!("Closing connection");
+        let receiver = self
+            .sender
+            .write_channel
+            .send_confirmed(Packet::with_str(PacketType::Close, ""))
+            .await?;
+        Delivery { receiver }.await
     }
 
     pub fn sender(&mut self) -> Sender {
-        Sender {
-            write_channel: self.write_channel.clone(),
-        }
+        self.sender.clone()
     }
 }
 
+/// The maximum payload size, unless configured or announced by the server.
+const DEFAULT_MAX_PAYLOAD: usize = 1_000_000;
+
 task_local! {
     static PING_RECIEVED: Cell<bool> = Cell::new(true);
 }
 
 impl EngineIO {
+    /// Establish a new session with the first of the allowed transports,
+    /// giving up after the connect timeout.
+    async fn handshake(options: &ClientOptions) -> Result<(OpenPacket, Connection), EIOError> {
+        let handshake = async {
+            match options.transports[0] {
+                Transport::Polling => {
+                    let packet = Self::handshake_polling(options).await?;
+                    Ok((packet, Connection::Polling))
+                }
+                Transport::Websocket => {
+                    let (packet, ws) = Self::handshake_websocket(options).await?;
+                    Ok((packet, Connection::Websocket(Box::new(ws))))
+                }
+            }
+        };
+
+        match options.connect_timeout {
+            Some(timeout) => async_std::future::timeout(timeout, handshake)
+                .await
+                .map_err(|_| EIOError::Timeout)?,
+            None => handshake.await,
+        }
+    }
+
+    async fn handshake_polling(options: &ClientOptions) -> Result<OpenPacket, EIOError> {
+        let connect_url = options.url(Transport::Polling, None);
+        info!("Establishing connection to {}", connect_url);
+        let mut response = options
+            .with_headers(surf::get(&connect_url))
+            .await
+            .map_err(|err| EIOError::Unreachable(err.into()))?;
+        let bytes = response
+            .body_bytes()
+            .await
+            .map_err(|err| EIOError::Http(Arc::new(err)))?;
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            return Err(EIOError::from_handshake_response(status, &bytes));
+        }
+
+        let payload = Payload::decode(&bytes, options.protocol_version)
+            .map_err(|err| EIOError::MalformedOpenPacket(format!("{}", err)))?;
+        match payload.into_packets().into_iter().next() {
+            Some(packet) => Self::parse_open_packet(packet),
+            None => Err(EIOError::MalformedOpenPacket("Expected open packet".into())),
+        }
+    }
+
+    async fn handshake_websocket(
+        options: &ClientOptions,
+    ) -> Result<(OpenPacket, WebSocket), EIOError> {
+        let connect_url = options.url(Transport::Websocket, None);
+        info!("Establishing connection to {}", connect_url);
+        let mut ws = websocket::connect(&connect_url, &options.headers).await?;
+
+        // The server initiates the session by sending the open packet
+        while let Some(message) = ws.next().await {
+            let packet = websocket::decode(message?, options.protocol_version)
+                .map_err(|err| EIOError::MalformedOpenPacket(format!("{}", err)))?;
+            if let Some(packet) = packet {
+                return Ok((Self::parse_open_packet(packet)?, ws));
+            }
+        }
+
+        Err(EIOError::ConnectionLost)
+    }
+
+    fn parse_open_packet(packet: Packet) -> Result<OpenPacket, EIOError> {
+        match (packet.packet_type(), packet.data()) {
+            (PacketType::Open, PacketData::Str(string)) => serde_json::from_str(string)
+                .map_err(|err| EIOError::MalformedOpenPacket(format!("{}", err))),
+            _ => Err(EIOError::MalformedOpenPacket(format!(
+                "Expected open packet, got {:?}",
+                packet
+            ))),
+        }
+    }
+
+    /// Run sessions until one ends without an error,
+    /// or until reconnecting is no longer allowed.
+    async fn run(
+        mut open_pkt: OpenPacket,
+        options: Arc<ClientOptions>,
+        mut connection: Connection,
+        mut event_handler: impl EventHandler + Send + Sync,
+        write_channel: QueueSender,
+        mut receiver: QueueReceiver,
+    ) -> Result<(), EIOError> {
+        loop {
+            let err = match Self::fire_up(
+                open_pkt,
+                &options,
+                connection,
+                &mut event_handler,
+                write_channel.clone(),
+                &mut receiver,
+            )
+            .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            error!("Connection lost: {}", err);
+            let (packet, new_connection) =
+                Self::reconnect(&options, &mut event_handler, err).await?;
+            open_pkt = packet;
+            connection = new_connection;
+            event_handler.on_reconnected().await;
+        }
+    }
+
+    /// Try to establish a new session, waiting for the policy's
+    /// delay before each attempt. If no attempt succeeds, the error
+    /// of the last one is returned.
+    async fn reconnect(
+        options: &ClientOptions,
+        event_handler: &mut (impl EventHandler + Send + Sync),
+        mut last_err: EIOError,
+    ) -> Result<(OpenPacket, Connection), EIOError> {
+        let policy = &options.reconnect_policy;
+        let mut attempt = 0;
+
+        while policy.allows(attempt) {
+            let delay = policy.delay(attempt);
+            attempt += 1;
+            event_handler.on_reconnecting(attempt).await;
+            debug!("Reconnect attempt {} in {:?}", attempt, delay);
+            task::sleep(delay).await;
+
+            match Self::handshake(options).await {
+                Ok(result) => return Ok(result),
+                Err(err) => {
+                    error!("Reconnect attempt {} failed: {}", attempt, err);
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
     async fn fire_up(
         open_pkt: OpenPacket,
-        base_url: String,
-        mut event_handler: impl EventHandler + Send + Sync,
-        write_channel: mpsc::UnboundedSender<Packet>,
-        receiver: mpsc::UnboundedReceiver<Packet>,
+        options: &Arc<ClientOptions>,
+        connection: Connection,
+        event_handler: &mut (impl EventHandler + Send + Sync),
+        write_channel: QueueSender,
+        receiver: &mut QueueReceiver,
     ) -> Result<(), EIOError> {
+        let (pings, ping_receiver) = mpsc::unbounded();
+        let (closed, mut closed_receiver) = mpsc::unbounded();
+        // Stay within the limit of the server, even if we configured a higher one
+        let server_max_payload = open_pkt.maxPayload.map(|max| max as usize);
+        let max_payload = match (options.max_payload, server_max_payload) {
+            (Some(max), Some(server_max)) => max.min(server_max),
+            (max, server_max) => max.or(server_max).unwrap_or(DEFAULT_MAX_PAYLOAD),
+        };
         let config = EngineIO {
             sid: open_pkt.sid,
-            base_url: base_url.to_owned(),
+            options: Arc::clone(options),
+            write_channel,
+            pings,
             ping_interval: open_pkt.pingInterval,
             ping_timeout: open_pkt.pingTimeout,
+            upgrades: open_pkt.upgrades,
+            upgrading: AtomicBool::new(false),
+            max_payload,
+            closing: AtomicBool::new(false),
+            closed,
         };
 
-        let result = try_join!(
-            (&config).poll_loop(&mut event_handler),
-            (&config).ping_loop(write_channel),
-            (&config).write_loop(receiver),
-        );
+        // Once the session was closed, the remaining loops are stopped
+        let (result, reason) = {
+            let loops = async {
+                match options.protocol_version {
+                    ProtocolVersion::V3 => try_join!(
+                        config.transport_loop(connection, event_handler, receiver),
+                        config.ping_loop(),
+                    )
+                    .map(|_| ()),
+                    ProtocolVersion::V4 => try_join!(
+                        config.transport_loop(connection, event_handler, receiver),
+                        config.heartbeat_loop(ping_receiver),
+                    )
+                    .map(|_| ()),
+                }
+            }
+            .fuse();
+            pin_mut!(loops);
 
-        event_handler.on_disconnect().await;
+            select! {
+                result = loops => match closed_receiver.try_recv() {
+                    // The loops failed because the session was closed
+                    Ok(reason) => (Ok(()), reason),
+                    Err(_) => {
+                        let reason = match &result {
+                            Ok(()) => DisconnectReason::ClientClose,
+                            Err(err) => DisconnectReason::from(err.clone()),
+                        };
+                        (result, reason)
+                    }
+                },
+                reason = closed_receiver.next() => {
+                    (Ok(()), reason.unwrap_or(DisconnectReason::ClientClose))
+                },
+            }
+        };
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
-        }
-    }
+        info!("Disconnected: {:?}", reason);
+        event_handler.on_disconnect(reason).await;
 
-    fn get_url(&self) -> String {
-        let time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap();
-        format!(
-            "{}?transport=polling&EIO=3&sid={}&t={}.{}",
-            self.base_url,
-            self.sid,
-            time.as_secs(),
-            time.subsec_nanos()
-        )
+        result
     }
 
-    async fn ping_loop(
+    /// Exchange packets over the given connection. A polling connection
+    /// is upgraded to a websocket if the server offers it.
+    ///
+    /// The handler is told that we are connected once the loops started, so
+    /// that packets it sends from `on_connect` are written in the meantime.
+    async fn transport_loop(
         &self,
-        mut write_channel: mpsc::UnboundedSender<Packet>,
+        connection: Connection,
+        event_handler: &mut (impl EventHandler + Send + Sync),
+        receiver: &mut QueueReceiver,
     ) -> Result<(), EIOError> {
-        let timeout = std::time::Duration::new((self.ping_timeout / 1000) as u64, 0);
-        let interval = std::time::Duration::new(
-            (self.ping_interval / 1000 - self.ping_timeout / 1000) as u64,
-            0,
-        );
+        let (ws, connected) = match connection {
+            Connection::Websocket(ws) => (*ws, false),
+            Connection::Polling => {
+                let (upgraded_sender, upgraded_receiver) = oneshot::channel();
+                let (_, _, ws) = try_join!(
+                    async {
+                        event_handler.on_connect().await;
+                        self.poll_loop(event_handler).await
+                    },
+                    self.write_loop(receiver, upgraded_receiver),
+                    self.probe(upgraded_sender),
+                )?;
+
+                // Polling only stops without an error once the probe succeeded
+                let mut ws = match ws {
+                    Some(ws) => ws,
+                    None => return Ok(()),
+                };
+
+                // Polling is paused and all in-flight writes are done, so we can switch
+                // over. Everything still queued is flushed over the websocket.
+                ws.send(self.encode_ws(&Packet::with_str(PacketType::Upgrade, "")))
+                    .await?;
+                info!("Upgraded transport to websocket");
+                (ws, true)
+            }
+        };
+
+        let (sink, stream) = ws.split();
+        try_join!(
+            async {
+                if !connected {
+                    event_handler.on_connect().await;
+                }
+                self.ws_read_loop(stream, event_handler).await
+            },
+            self.ws_write_loop(sink, receiver),
+        )
+        .map(|_| ())
+    }
+
+    /// Probe a websocket connection with the current sid, as the first step of
+    /// the upgrade. On success, polling is paused and `upgraded` is notified.
+    /// A failed probe is not an error, we simply continue polling.
+    async fn probe(&self, upgraded: oneshot::Sender<()>) -> Result<Option<WebSocket>, EIOError> {
+        let offered = self.upgrades.iter().any(|upgrade| upgrade == "websocket");
+        if !offered || !self.options.transports.contains(&Transport::Websocket) {
+            return Ok(None);
+        }
+
+        let timeout = Duration::from_millis(self.ping_timeout.into());
+        match async_std::future::timeout(timeout, self.try_probe()).await {
+            Ok(Ok(ws)) => {
+                self.upgrading.store(true, Ordering::SeqCst);
+                let _ = upgraded.send(());
+                Ok(Some(ws))
+            }
+            Ok(Err(err)) => {
+                error!("Websocket probe failed, continue polling: {}", err);
+                Ok(None)
+            }
+            Err(_) => {
+                error!("Websocket probe timed out, continue polling");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn try_probe(&self) -> Result<WebSocket, EIOError> {
+        let url = self.get_url(Transport::Websocket);
+        debug!("Probing {}", url);
+        let mut ws = websocket::connect(&url, &self.options.headers).await?;
+        ws.send(self.encode_ws(&Packet::with_str(PacketType::Ping, "probe")))
+            .await?;
+
+        while let Some(message) = ws.next().await {
+            if let Some(packet) = self.decode_ws(message?)? {
+                return match (packet.packet_type(), packet.data()) {
+                    (PacketType::Pong, PacketData::Str(data)) if data == "probe" => Ok(ws),
+                    _ => Err(EIOError::Protocol(format!(
+                        "Expected probe pong, got {:?}",
+                        packet
+                    ))),
+                };
+            }
+        }
+
+        Err(EIOError::ConnectionLost)
+    }
+
+    fn get_url(&self, transport: Transport) -> String {
+        self.options.url(transport, Some(&self.sid))
+    }
+
+    fn encode_ws(&self, packet: &Packet) -> Message {
+        websocket::encode(packet, self.options.protocol_version)
+    }
+
+    fn decode_ws(&self, message: Message) -> Result<Option<Packet>, EIOError> {
+        websocket::decode(message, self.options.protocol_version)
+    }
+
+    async fn ping_loop(&self) -> Result<(), EIOError> {
+        let mut write_channel = self.write_channel.clone();
+        let timeout = Duration::from_millis(u64::from(self.ping_timeout));
+        // Servers may advertise a timeout longer than the interval
+        let interval = Duration::from_millis(u64::from(self.ping_interval)).saturating_sub(timeout);
         debug!("Interval {:?}, Timeout {:?}", interval, timeout);
 
         loop {
             write_channel
                 .send(Packet::with_str(PacketType::Ping, "probe"))
-                .await
-                .unwrap();
+                .await?;
 
             PING_RECIEVED.with(|recv| recv.set(false));
 
@@ -236,42 +771,191 @@ impl EngineIO {
         }
     }
 
+    /// In protocol version 4 the server sends the pings, which `handle_packet`
+    /// answers. We expect the next ping within `pingInterval + pingTimeout`.
+    async fn heartbeat_loop(&self, mut pings: mpsc::UnboundedReceiver<()>) -> Result<(), EIOError> {
+        let timeout =
+            Duration::from_millis(u64::from(self.ping_interval) + u64::from(self.ping_timeout));
+        debug!("Expecting pings within {:?}", timeout);
+
+        loop {
+            match async_std::future::timeout(timeout, pings.next()).await {
+                Ok(Some(())) => (),
+                Ok(None) => return Ok(()),
+                Err(_) => {
+                    error!("Ping not received, aborting");
+                    return Err(EIOError::PingNotReceived);
+                }
+            }
+        }
+    }
+
     async fn poll_loop(
         &self,
         event_handler: &mut (impl EventHandler + Send + Sync),
     ) -> Result<(), EIOError> {
-        // When the poll loop starts, we are connected
-        event_handler.on_connect().await;
-
-        loop {
-            let url = Self::get_url(&self);
+        // The server answers the last poll with a noop once we probed,
+        // after which we stop polling
+        while !self.upgrading.load(Ordering::SeqCst) {
+            let url = self.get_url(Transport::Polling);
             debug!("Polling {}", url);
 
-            let bytes = match surf::get(&url).recv_bytes().await {
+            let request = self.options.with_headers(surf::get(&url));
+            let bytes = match request.recv_bytes().await {
                 Ok(bytes) => bytes,
                 Err(exc) => {
                     return Err(exc.into());
                 }
             };
 
-            let payload = Payload::new(&bytes)?;
+            let payload = Payload::decode(&bytes, self.options.protocol_version)?;
 
             for packet in payload.into_packets() {
                 debug!("Received {:?}", packet);
                 Self::handle_packet(self, packet, event_handler).await;
             }
         }
+
+        debug!("Paused polling");
+        Ok(())
     }
 
+    /// Send packets via polling until the channel is closed, or until
+    /// `upgraded` signals that the transport was upgraded.
+    /// All packets queued in the meantime are sent with the next request.
     async fn write_loop(
         &self,
-        mut receiver: mpsc::UnboundedReceiver<Packet>,
+        receiver: &mut QueueReceiver,
+        upgraded: oneshot::Receiver<()>,
+    ) -> Result<(), EIOError> {
+        let mut upgraded = upgraded.fuse();
+        // A packet that did not fit into the previous payload
+        let mut pending = None;
+
+        loop {
+            let packet = match pending.take() {
+                Some(packet) => packet,
+                // Batching may have seen that the queue was closed
+                None if receiver.is_terminated() => break,
+                None => select! {
+                    queued = receiver.next() => match queued {
+                        Some(queued) => queued,
+                        None => break,
+                    },
+                    result = upgraded => match result {
+                        Ok(()) => return Ok(()),
+                        // No upgrade is going to happen
+                        Err(_) => continue,
+                    },
+                },
+            };
+
+            let (packets, confirmations): (Vec<_>, Vec<_>) = self
+                .batch(packet, receiver, &mut pending)
+                .into_iter()
+                .map(|queued| (queued.packet, queued.confirmation))
+                .unzip();
+            let closing = self.start_closing(&packets);
+            let payload = Payload::from_packets(packets);
+            debug!("Sending {:?}", payload);
+            let url = self.get_url(Transport::Polling);
+            let request = self.options.with_headers(surf::post(&url));
+            let response = match (self.options.protocol_version, self.options.payload_encoding) {
+                (ProtocolVersion::V3, PayloadEncoding::Binary) => {
+                    request.body_bytes(payload.encode_binary()).await
+                }
+                (ProtocolVersion::V3, PayloadEncoding::Text) => {
+                    request.body_string(payload.encode()).await
+                }
+                (ProtocolVersion::V4, _) => request.body_string(payload.encode_v4()).await,
+            };
+            let result = match response {
+                Ok(response) if response.status().is_success() => Ok(()),
+                Ok(response) => Err(EIOError::HttpStatus(response.status().as_u16())),
+                Err(err) => Err(EIOError::from(err)),
+            };
+
+            for confirmation in confirmations {
+                confirmation.confirm(result.clone());
+            }
+            result?;
+            if closing {
+                let _ = self.closed.unbounded_send(DisconnectReason::ClientClose);
+            }
+        }
+        debug!("Exit write loop");
+        Ok(())
+    }
+
+    /// Batch `first` and the packets queued after it into a payload of at most
+    /// `max_payload` bytes. The first packet that exceeds it is set as `pending`.
+    fn batch(
+        &self,
+        first: Queued,
+        receiver: &mut QueueReceiver,
+        pending: &mut Option<Queued>,
+    ) -> Vec<Queued> {
+        let version = self.options.protocol_version;
+        let encoding = self.options.payload_encoding;
+        let mut size = Payload::encoded_len(&first.packet, version, encoding);
+        let mut batch = vec![first];
+
+        while let Some(queued) = receiver.try_recv() {
+            size += Payload::encoded_len(&queued.packet, version, encoding);
+            if size > self.max_payload {
+                *pending = Some(queued);
+                break;
+            }
+            batch.push(queued);
+        }
+
+        batch
+    }
+
+    /// Whether `packets` contain the close packet of the client,
+    /// after which a close packet of the server is expected.
+    fn start_closing(&self, packets: &[Packet]) -> bool {
+        let closing = packets
+            .iter()
+            .any(|packet| *packet.packet_type() == PacketType::Close);
+        if closing {
+            self.closing.store(true, Ordering::SeqCst);
+        }
+        closing
+    }
+
+    async fn ws_read_loop(
+        &self,
+        mut stream: SplitStream<WebSocket>,
+        event_handler: &mut (impl EventHandler + Send + Sync),
     ) -> Result<(), EIOError> {
-        while let Some(packet) = receiver.next().await {
-            debug!("Sending {:?}", packet);
-            let payload = Payload::from_packet(packet);
-            let url = Self::get_url(&self);
-            let _response = surf::post(&url).body_bytes(payload.encode_binary()).await;
+        while let Some(message) = stream.next().await {
+            if let Some(packet) = self.decode_ws(message?)? {
+                debug!("Received {:?}", packet);
+                Self::handle_packet(self, packet, event_handler).await;
+            }
+        }
+
+        Err(EIOError::ConnectionLost)
+    }
+
+    async fn ws_write_loop(
+        &self,
+        mut sink: SplitSink<WebSocket, Message>,
+        receiver: &mut QueueReceiver,
+    ) -> Result<(), EIOError> {
+        while let Some(queued) = receiver.next().await {
+            debug!("Sending {:?}", queued.packet);
+            let closing = self.start_closing(std::slice::from_ref(&queued.packet));
+            let result = sink
+                .send(self.encode_ws(&queued.packet))
+                .await
+                .map_err(EIOError::from);
+            queued.confirmation.confirm(result.clone());
+            result?;
+            if closing {
+                let _ = self.closed.unbounded_send(DisconnectReason::ClientClose);
+            }
         }
         debug!("Exit write loop");
         Ok(())
@@ -283,11 +967,21 @@ impl EngineIO {
         event_handler: &mut (dyn EventHandler + Send + Sync),
     ) {
         match packet.packet_type() {
+            PacketType::Ping if self.options.protocol_version == ProtocolVersion::V4 => {
+                let _ = self.pings.unbounded_send(());
+                let _ = self
+                    .write_channel
+                    .clone()
+                    .send(Packet::with_str(PacketType::Pong, ""))
+                    .await;
+            }
             PacketType::Pong => {
                 PING_RECIEVED.with(|recv| recv.set(true));
             }
+            // The session ends once our own close packet was written
+            PacketType::Close if self.closing.load(Ordering::SeqCst) => (),
             PacketType::Close => {
-                event_handler.on_disconnect().await;
+                let _ = self.closed.unbounded_send(DisconnectReason::ServerClose);
             }
             PacketType::Message => {
                 event_handler.on_message(packet.into_data()).await;
@@ -299,3 +993,688 @@ impl EngineIO {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconnect::ReconnectPolicy;
+    use crate::testing::{self, ws_server, Event, RecordingHandler, OPEN};
+    use std::time::Instant;
+
+    /// Starts a stand-in server that answers the probe of the client with `reply`.
+    async fn probe_server(reply: &'static str) -> String {
+        ws_server(&[], move |message| match message {
+            Message::Text(text) if text.as_str() == "2probe" => Some(vec![Message::text(reply)]),
+            _ => Some(vec![]),
+        })
+        .await
+    }
+
+    fn engine_io(url: String) -> EngineIO {
+        EngineIO {
+            sid: "abc".into(),
+            options: Arc::new(ClientBuilder::new(&url).build().unwrap()),
+            write_channel: queue::queue(1).0,
+            pings: mpsc::unbounded().0,
+            ping_interval: 25000,
+            ping_timeout: 5000,
+            upgrades: vec!["websocket".into()],
+            upgrading: AtomicBool::new(false),
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            closing: AtomicBool::new(false),
+            closed: mpsc::unbounded().0,
+        }
+    }
+
+    /// Starts a stand-in server that drops the first connection once the
+    /// client sends a message, and answers pings and echoes on all others.
+    async fn flaky_server() -> String {
+        let dropped = Arc::new(AtomicBool::new(false));
+        ws_server(&[OPEN], move |message| {
+            if dropped.swap(true, Ordering::SeqCst) {
+                testing::echo(message)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    #[async_std::test]
+    async fn test_reconnect_keeps_senders_valid() {
+        let url = flaky_server().await;
+        let (handler, mut events) = RecordingHandler::new();
+        let policy = ReconnectPolicy {
+            max_attempts: Some(3),
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            randomization_factor: 0.0,
+        };
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .reconnect_policy(policy)
+            .connect(handler)
+            .await
+            .unwrap();
+        let mut sender = client.sender();
+
+        assert_eq!(events.next().await, Some(Event::Connect));
+        assert!(matches!(
+            events.next().await,
+            Some(Event::Disconnect(DisconnectReason::TransportError(_)))
+        ));
+        for expected in [Event::Reconnecting(1), Event::Reconnected, Event::Connect] {
+            assert_eq!(events.next().await, Some(expected));
+        }
+
+        sender.emit_str("hello".into()).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(Event::Message(PacketData::Str("hello".into())))
+        );
+    }
+
+    /// Starts a stand-in v4 server that pings the client right after
+    /// the handshake and echoes messages once it got the pong.
+    async fn v4_server() -> String {
+        const OPEN_V4: &str = r#"0{"sid":"abc","upgrades":[],"pingInterval":25000,"pingTimeout":5000,"maxPayload":1000000}"#;
+        // The pong and the messages may arrive in any order
+        let mut pong_received = false;
+        let mut pending = Vec::new();
+        ws_server(&[OPEN_V4, "2"], move |message| {
+            if message == Message::text("3") {
+                pong_received = true;
+            } else {
+                pending.push(message);
+            }
+            match pong_received {
+                true => Some(std::mem::take(&mut pending)),
+                false => Some(vec![]),
+            }
+        })
+        .await
+    }
+
+    #[async_std::test]
+    async fn test_v4_answers_server_ping() {
+        let url = v4_server().await;
+        let (handler, mut events) = RecordingHandler::new();
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect(handler)
+            .await
+            .unwrap();
+        assert_eq!(events.next().await, Some(Event::Connect));
+
+        client.emit_bytes(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(Event::Message(PacketData::Bytes(vec![1, 2, 3])))
+        );
+    }
+
+    /// Starts a stand-in v4 server that never sends a ping.
+    async fn silent_v4_server() -> String {
+        const OPEN_V4: &str = r#"0{"sid":"abc","upgrades":[],"pingInterval":50,"pingTimeout":50}"#;
+        ws_server(&[OPEN_V4], testing::ignore).await
+    }
+
+    /// Starts a stand-in v4 server that forwards the text messages it receives
+    /// to the returned channel, and closes the session on a close packet.
+    async fn recording_server() -> (String, mpsc::UnboundedReceiver<String>) {
+        let (messages, received) = mpsc::unbounded();
+        let url = ws_server(&[OPEN], move |message| match message {
+            Message::Text(text) => {
+                messages.unbounded_send(text.to_string()).unwrap();
+                match text.as_str() {
+                    "1" => Some(vec![Message::text("1")]),
+                    _ => Some(vec![]),
+                }
+            }
+            _ => None,
+        })
+        .await;
+
+        (url, received)
+    }
+
+    /// Emits more messages on connect than fit into the queue.
+    struct EagerHandler {
+        sender: Sender,
+    }
+
+    #[async_trait]
+    impl EventHandler for EagerHandler {
+        async fn on_connect(&mut self) {
+            for i in 0..5 {
+                self.sender.emit_str(i.to_string()).await.unwrap();
+            }
+        }
+
+        async fn on_disconnect(&mut self, _reason: DisconnectReason) {}
+
+        async fn on_message(&mut self, _data: PacketData) {}
+    }
+
+    #[async_std::test]
+    async fn test_emits_on_connect_do_not_wait_for_the_loops() {
+        let (url, received) = recording_server().await;
+        let options = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .queue_capacity(1)
+            .build()
+            .unwrap();
+
+        let _client = Client::open_with(options, |sender| EagerHandler { sender })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            received.take(5).collect::<Vec<_>>().await,
+            vec!["40", "41", "42", "43", "44"]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_close_flushes_and_sends_close_packet() {
+        let (url, received) = recording_server().await;
+        let (handler, events) = RecordingHandler::new();
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect(handler)
+            .await
+            .unwrap();
+        client.emit_str("hello".into()).await.unwrap();
+        client.close().await.unwrap();
+        client.join().await.unwrap();
+
+        assert_eq!(
+            received.take(2).collect::<Vec<_>>().await,
+            vec!["4hello", "1"]
+        );
+        assert_eq!(
+            events.collect::<Vec<_>>().await,
+            vec![
+                Event::Connect,
+                Event::Disconnect(DisconnectReason::ClientClose)
+            ]
+        );
+    }
+
+    /// Starts a stand-in v4 server that closes the session right after the handshake.
+    async fn closing_server() -> String {
+        ws_server(&[OPEN, "1"], |_| None).await
+    }
+
+    #[async_std::test]
+    async fn test_server_close_is_reported_once() {
+        let url = closing_server().await;
+        let (handler, events) = RecordingHandler::new();
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect(handler)
+            .await
+            .unwrap();
+        client.join().await.unwrap();
+
+        assert_eq!(
+            events.collect::<Vec<_>>().await,
+            vec![
+                Event::Connect,
+                Event::Disconnect(DisconnectReason::ServerClose)
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_v4_times_out_without_server_ping() {
+        let url = silent_v4_server().await;
+        let (handler, mut events) = RecordingHandler::new();
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect(handler)
+            .await
+            .unwrap();
+
+        let err = client.join().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<EIOError>(),
+            Some(EIOError::PingNotReceived)
+        ));
+        assert_eq!(events.next().await, Some(Event::Connect));
+        assert_eq!(
+            events.next().await,
+            Some(Event::Disconnect(DisconnectReason::PingTimeout))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_stream_and_sink() {
+        let url = v4_server().await;
+        let (mut sender, mut messages) = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect_stream()
+            .await
+            .unwrap();
+
+        let data = futures::stream::iter(vec![
+            Ok(PacketData::Str("hello".into())),
+            Ok(PacketData::Bytes(vec![1, 2, 3])),
+        ]);
+        sender.send_all(&mut data.boxed()).await.unwrap();
+
+        let echoed: Vec<_> = messages.by_ref().take(2).collect().await;
+        assert_eq!(
+            echoed.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![
+                PacketData::Str("hello".into()),
+                PacketData::Bytes(vec![1, 2, 3])
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_stream_ends_with_connection_error() {
+        let url = silent_v4_server().await;
+        let (mut sender, mut messages) = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect_stream()
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            messages.next().await,
+            Some(Err(EIOError::PingNotReceived))
+        ));
+        assert!(messages.next().await.is_none());
+        assert!(matches!(
+            sender.emit_str("too late".into()).await,
+            Err(EIOError::Closed)
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_dropped_stream_closes_connection() {
+        let (url, mut received) = recording_server().await;
+        let (sender, messages) = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect_stream()
+            .await
+            .unwrap();
+
+        drop(sender);
+        drop(messages);
+
+        assert_eq!(received.next().await, Some("1".into()));
+    }
+
+    #[async_std::test]
+    async fn test_stream_close() {
+        let (url, _received) = recording_server().await;
+        let (mut sender, mut messages) = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect_stream()
+            .await
+            .unwrap();
+
+        messages.close().await.unwrap();
+
+        assert!(messages.next().await.is_none());
+        assert!(matches!(
+            sender.emit_str("too late".into()).await,
+            Err(EIOError::Closed)
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_queued_packets_are_batched() {
+        let mut config = engine_io("http://127.0.0.1:1/engine.io/".into());
+        // Fits two of the packets, which take 7 bytes each
+        config.max_payload = 20;
+        let (mut sender, mut receiver) = queue::queue(8);
+        for data in &["one", "two", "six"] {
+            sender
+                .send(Packet::with_str(PacketType::Message, data))
+                .await
+                .unwrap();
+        }
+
+        let mut pending = None;
+        let first = receiver.next().await.unwrap();
+        let batch = config.batch(first, &mut receiver, &mut pending);
+        let payload = Payload::from_packets(batch.into_iter().map(|q| q.packet).collect());
+
+        assert_eq!(payload.packets().len(), 2);
+        assert_eq!(payload.encode_binary().len(), 14);
+        assert_eq!(
+            pending.as_ref().map(|q| &q.packet),
+            Some(&Packet::with_str(PacketType::Message, "six"))
+        );
+        assert_eq!(sender.depth(), 0);
+
+        let batch = config.batch(pending.take().unwrap(), &mut receiver, &mut pending);
+        assert_eq!(batch.len(), 1);
+        assert!(pending.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_confirmed_emit_is_delivered() {
+        let url = v4_server().await;
+        let (mut sender, mut messages) = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4)
+            .connect_stream()
+            .await
+            .unwrap();
+
+        let delivery = sender.emit_confirmed(PacketData::Str("hello".into())).await;
+        assert!(delivery.await.is_ok());
+        assert_eq!(
+            messages.next().await.unwrap().unwrap(),
+            PacketData::Str("hello".into())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_rejected_post_fails_write() {
+        let config = engine_io(testing::http_server("400 Bad Request", "").await);
+        let (sender, mut receiver) = queue::queue(8);
+        let mut sender = Sender {
+            write_channel: sender,
+        };
+        let delivery = sender.emit_confirmed(PacketData::Str("lost".into())).await;
+        drop(sender);
+
+        let (_upgraded, upgraded_receiver) = oneshot::channel();
+        let result = config.write_loop(&mut receiver, upgraded_receiver).await;
+        assert_eq!(result, Err(EIOError::HttpStatus(400)));
+        assert_eq!(delivery.await, Err(EIOError::HttpStatus(400)));
+    }
+
+    #[async_std::test]
+    async fn test_successful_probe_pauses_polling() {
+        let config = engine_io(probe_server("3probe").await);
+        let (sender, receiver) = oneshot::channel();
+
+        let ws = config.probe(sender).await.unwrap();
+
+        assert!(ws.is_some());
+        assert!(config.upgrading.load(Ordering::SeqCst));
+        assert_eq!(receiver.await, Ok(()));
+    }
+
+    #[async_std::test]
+    async fn test_failed_probe_continues_polling() {
+        let config = engine_io(probe_server("6").await);
+        let (sender, receiver) = oneshot::channel();
+
+        let ws = config.probe(sender).await.unwrap();
+
+        assert!(ws.is_none());
+        assert!(!config.upgrading.load(Ordering::SeqCst));
+        assert!(receiver.await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_no_probe_without_websocket_upgrade() {
+        let mut config = engine_io("http://127.0.0.1:1/engine.io/".into());
+        config.upgrades.clear();
+        let (sender, _receiver) = oneshot::channel();
+
+        assert!(config.probe(sender).await.unwrap().is_none());
+    }
+
+    /// The state of an [`upgrade_server`](fn.upgrade_server.html).
+    #[derive(Clone)]
+    struct Upgrade {
+        /// What the server received, in order.
+        log: mpsc::UnboundedSender<String>,
+        /// Set once a payload was posted.
+        posted: Arc<AtomicBool>,
+        /// Set once the probe was answered.
+        probed: Arc<AtomicBool>,
+    }
+
+    async fn until(flag: &AtomicBool) {
+        while !flag.load(Ordering::SeqCst) {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    impl Upgrade {
+        async fn handle(self, mut request: http_types::Request) -> http_types::Response {
+            let polled = request.url().query_pairs().any(|(key, _)| key == "sid");
+            let body = match request.method() {
+                // The handshake
+                http_types::Method::Get if !polled => {
+                    r#"0{"sid":"abc","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#
+                        .to_owned()
+                }
+                // Answer the pending poll with a noop once we were probed
+                http_types::Method::Get => {
+                    self.log.unbounded_send("GET".into()).unwrap();
+                    until(&self.probed).await;
+                    "6".to_owned()
+                }
+                // Keep the payload in flight until we were probed
+                _ => {
+                    let body = request.body_string().await.unwrap();
+                    for packet in body.split('\x1e') {
+                        self.log.unbounded_send(format!("POST {}", packet)).unwrap();
+                    }
+                    self.posted.store(true, Ordering::SeqCst);
+                    until(&self.probed).await;
+                    self.log.unbounded_send("POST answered".into()).unwrap();
+                    "ok".to_owned()
+                }
+            };
+            let mut response = http_types::Response::new(http_types::StatusCode::Ok);
+            response.set_body(body);
+            response
+        }
+
+        async fn websocket(self, stream: async_std::net::TcpStream) {
+            let mut ws = async_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                self.log.unbounded_send(format!("WS {}", text)).unwrap();
+                match text.as_str() {
+                    "2probe" => {
+                        until(&self.posted).await;
+                        ws.send(Message::text("3probe")).await.unwrap();
+                        self.probed.store(true, Ordering::SeqCst);
+                    }
+                    "5" => ws.send(Message::text("4upgraded")).await.unwrap(),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Starts a stand-in v4 server that offers to upgrade to a websocket on the same
+    /// port. It answers the probe once a payload was posted, which it answers in turn
+    /// only after the probe, so that the payload is in flight during the upgrade.
+    async fn upgrade_server() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (log, received) = mpsc::unbounded();
+        let upgrade = Upgrade {
+            log,
+            posted: Arc::new(AtomicBool::new(false)),
+            probed: Arc::new(AtomicBool::new(false)),
+        };
+
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let upgrade = upgrade.clone();
+                task::spawn(async move {
+                    let mut head = [0; 4096];
+                    let len = stream.peek(&mut head).await.unwrap();
+                    let head = String::from_utf8_lossy(&head[..len]).to_lowercase();
+                    if head.contains("upgrade: websocket") {
+                        upgrade.websocket(stream).await;
+                    } else {
+                        let _ = async_h1::accept(stream, |request| {
+                            let upgrade = upgrade.clone();
+                            async move { Ok(upgrade.handle(request).await) }
+                        })
+                        .await;
+                    }
+                });
+            }
+        });
+
+        (format!("http://{}/engine.io/", addr), received)
+    }
+
+    /// Add the entries of `log` to `received` up to the given one.
+    async fn receive_until(
+        log: &mut mpsc::UnboundedReceiver<String>,
+        received: &mut Vec<String>,
+        entry: &str,
+    ) {
+        while received.last().map(String::as_str) != Some(entry) {
+            received.push(log.next().await.unwrap());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_upgrade_from_polling() {
+        let (url, mut log) = upgrade_server().await;
+        let (handler, events) = RecordingHandler::new();
+        let mut messages = testing::messages(events);
+
+        let mut client = Client::builder(&url)
+            .protocol_version(ProtocolVersion::V4)
+            .connect(handler)
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        client.emit_str("one".into()).await.unwrap();
+        // Queue another message while the first one is in flight
+        receive_until(&mut log, &mut received, "POST 4one").await;
+        client.emit_str("two".into()).await.unwrap();
+        assert_eq!(
+            messages.next().await,
+            Some(PacketData::Str("upgraded".into()))
+        );
+        client.emit_str("three".into()).await.unwrap();
+        receive_until(&mut log, &mut received, "WS 4three").await;
+
+        let position = |entry: &str| received.iter().position(|e| e == entry).unwrap();
+        let upgraded = position("WS 5");
+        // Polling was paused, and the payload in flight was answered before the upgrade
+        assert!(received[upgraded..].iter().all(|e| !e.starts_with("GET")));
+        assert!(position("POST answered") < upgraded);
+        // The queued message was sent exactly once, over websocket if after the upgrade
+        let sent_two: Vec<_> = received.iter().filter(|e| e.ends_with(" 4two")).collect();
+        assert_eq!(sent_two.len(), 1);
+        assert!(sent_two[0] == "POST 4two" || position("WS 4two") > upgraded);
+        // Later traffic goes over the websocket only
+        let ws: Vec<_> = received
+            .iter()
+            .filter(|e| e.starts_with("WS ") && *e != "WS 4two")
+            .collect();
+        assert_eq!(ws, ["WS 2probe", "WS 5", "WS 4three"]);
+    }
+
+    #[async_std::test]
+    async fn test_ping_timeout_longer_than_interval() {
+        let mut config = engine_io("http://127.0.0.1:1/engine.io/".into());
+        let (write_channel, _receiver) = queue::queue(1);
+        config.write_channel = write_channel;
+        config.ping_interval = 50;
+        config.ping_timeout = 100;
+
+        let started = Instant::now();
+        let result = config.ping_loop().await;
+        assert!(matches!(result, Err(EIOError::PongNotReceived)));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    async fn handshake_error(url: &str, transport: Transport) -> EIOError {
+        Client::builder(url)
+            .transports(&[transport])
+            .protocol_version(ProtocolVersion::V4)
+            .connect_stream()
+            .await
+            .err()
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_handshake_errors() {
+        let err = handshake_error("http://127.0.0.1:1/engine.io/", Transport::Polling).await;
+        assert!(matches!(err, EIOError::Unreachable(_)));
+
+        let url = testing::http_server("503 Service Unavailable", "Try again later").await;
+        let err = handshake_error(&url, Transport::Polling).await;
+        assert_eq!(err, EIOError::HttpStatus(503));
+
+        let body = r#"{"code":5,"message":"Unsupported protocol version"}"#;
+        let url = testing::http_server("400 Bad Request", body).await;
+        let err = handshake_error(&url, Transport::Polling).await;
+        assert_eq!(
+            err,
+            EIOError::UnsupportedProtocol("Unsupported protocol version".into())
+        );
+
+        let url =
+            testing::http_server("400 Bad Request", r#"{"code":3,"message":"Bad request"}"#).await;
+        let expected = EIOError::Handshake {
+            code: 3,
+            message: "Bad request".into(),
+        };
+        assert_eq!(handshake_error(&url, Transport::Polling).await, expected);
+        assert_eq!(handshake_error(&url, Transport::Websocket).await, expected);
+
+        let url = testing::http_server("200 OK", r#"0{"sid":"#).await;
+        let err = handshake_error(&url, Transport::Polling).await;
+        assert!(matches!(err, EIOError::MalformedOpenPacket(_)));
+
+        let url = testing::http_server("200 OK", "4hello").await;
+        let err = handshake_error(&url, Transport::Polling).await;
+        assert!(matches!(err, EIOError::MalformedOpenPacket(_)));
+    }
+
+    #[async_std::test]
+    async fn test_headers_are_sent_with_polling_requests() {
+        use async_std::io::prelude::{ReadExt, WriteExt};
+        use async_std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/engine.io/", listener.local_addr().unwrap());
+        let request = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await.unwrap();
+            let response = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..len]).to_lowercase()
+        });
+
+        let name = format!("X-{}", "Token");
+        let result = Client::builder(&url)
+            .header(&name, "secret")
+            .transports(&[Transport::Polling])
+            .connect_stream()
+            .await;
+        assert!(result.is_err());
+        assert!(request.await.contains("x-token: secret\r\n"));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::packet::PacketDecodeError;
+use crate::payload::PayloadDecodeError;
+use async_tungstenite::tungstenite;
+use serde::Deserialize;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+/// An error of the client. Errors caused by another error,
+/// like that of the HTTP client, keep it as their source.
+#[derive(Debug, Clone)]
+pub enum EIOError {
+    /// The server could not be reached, e.g. because the connection was refused.
+    Unreachable(Arc<dyn Error + Send + Sync>),
+    /// A request of the polling transport failed.
+    Http(Arc<dyn Error + Send + Sync>),
+    /// The websocket transport failed.
+    Websocket(Arc<dyn Error + Send + Sync>),
+    /// The server answered a request with an HTTP error status.
+    HttpStatus(u16),
+    /// The server rejected the handshake with an engine.io error,
+    /// e.g. code 3 for a bad request.
+    Handshake { code: u32, message: String },
+    /// The server does not support the protocol version of the client.
+    UnsupportedProtocol(String),
+    /// The open packet of the server could not be parsed.
+    MalformedOpenPacket(String),
+    /// A payload or packet of the server could not be decoded.
+    Decode(PayloadDecodeError),
+    /// A violation of the engine.io protocol, e.g. an unexpected packet.
+    Protocol(String),
+    /// The server closed the connection unexpectedly.
+    ConnectionLost,
+    /// Connecting took longer than the
+    /// [`connect_timeout`](struct.ClientBuilder.html#method.connect_timeout).
+    Timeout,
+    /// An error to signal that no pong was
+    /// received from the other end.
+    PongNotReceived,
+    /// An error to signal that the server did not
+    /// send a ping in time.
+    PingNotReceived,
+    /// The write queue is full, see
+    /// [`queue_capacity`](struct.ClientBuilder.html#method.queue_capacity).
+    QueueFull,
+    /// The connection is closed, so no more messages can be sent.
+    Closed,
+    /// The options of the client are invalid, e.g. no transport is allowed.
+    InvalidOptions(String),
+}
+
+impl Error for EIOError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EIOError::Unreachable(err) | EIOError::Http(err) | EIOError::Websocket(err) => {
+                Some(err.as_ref())
+            }
+            EIOError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EIOError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EIOError::Unreachable(err) => write!(f, "Server unreachable: {}", err),
+            EIOError::Http(err) => write!(f, "HTTP request failed: {}", err),
+            EIOError::Websocket(err) => write!(f, "Websocket failed: {}", err),
+            EIOError::HttpStatus(status) => write!(f, "Server responded with {}", status),
+            EIOError::Handshake { code, message } => {
+                write!(f, "Handshake rejected with code {}: {}", code, message)
+            }
+            EIOError::UnsupportedProtocol(str_) => write!(f, "Unsupported protocol: {}", str_),
+            EIOError::MalformedOpenPacket(str_) => write!(f, "Malformed open packet: {}", str_),
+            EIOError::Decode(err) => write!(f, "Invalid payload: {}", err),
+            EIOError::Protocol(str_) => write!(f, "{}", str_),
+            EIOError::ConnectionLost => write!(f, "Connection lost"),
+            EIOError::Timeout => write!(f, "Connect timed out"),
+            EIOError::PongNotReceived => write!(f, "Pong was not received"),
+            EIOError::PingNotReceived => write!(f, "Ping was not received"),
+            EIOError::QueueFull => write!(f, "Write queue is full"),
+            EIOError::Closed => write!(f, "Connection closed"),
+            EIOError::InvalidOptions(str_) => write!(f, "Invalid options: {}", str_),
+        }
+    }
+}
+
+/// Errors are equal if they are of the same kind with the same message,
+/// as the source errors they keep cannot be compared themselves.
+impl PartialEq for EIOError {
+    fn eq(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+            && self.to_string() == other.to_string()
+    }
+}
+
+/// An engine.io error, as sent by the server to reject a request,
+/// e.g. `{"code":1,"message":"Session ID unknown"}`.
+#[derive(Deserialize)]
+struct ErrorMessage {
+    code: u32,
+    message: String,
+}
+
+/// The error code of the server for an unsupported protocol version.
+const UNSUPPORTED_PROTOCOL_VERSION: u32 = 5;
+
+impl EIOError {
+    /// The error of a handshake that the server answered with the
+    /// HTTP error `status`, taken from the engine.io error in `body` if any.
+    pub(crate) fn from_handshake_response(status: u16, body: &[u8]) -> Self {
+        match serde_json::from_slice::<ErrorMessage>(body) {
+            Ok(error) if error.code == UNSUPPORTED_PROTOCOL_VERSION => {
+                EIOError::UnsupportedProtocol(error.message)
+            }
+            Ok(error) => EIOError::Handshake {
+                code: error.code,
+                message: error.message,
+            },
+            Err(_) => EIOError::HttpStatus(status),
+        }
+    }
+}
+
+impl From<surf::Exception> for EIOError {
+    fn from(err: surf::Exception) -> Self {
+        EIOError::Http(err.into())
+    }
+}
+
+impl From<tungstenite::Error> for EIOError {
+    fn from(err: tungstenite::Error) -> Self {
+        EIOError::Websocket(Arc::new(err))
+    }
+}
+
+impl From<PayloadDecodeError> for EIOError {
+    fn from(err: PayloadDecodeError) -> Self {
+        EIOError::Decode(err)
+    }
+}
+
+impl From<PacketDecodeError> for EIOError {
+    fn from(err: PacketDecodeError) -> Self {
+        EIOError::Decode(PayloadDecodeError::InvalidPacket(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_response_errors() {
+        let body = br#"{"code":3,"message":"Bad request"}"#;
+        assert_eq!(
+            EIOError::from_handshake_response(400, body),
+            EIOError::Handshake {
+                code: 3,
+                message: "Bad request".into()
+            }
+        );
+        let body = br#"{"code":5,"message":"Unsupported protocol version"}"#;
+        assert!(matches!(
+            EIOError::from_handshake_response(400, body),
+            EIOError::UnsupportedProtocol(_)
+        ));
+        assert_eq!(
+            EIOError::from_handshake_response(502, b"<html>Bad Gateway</html>"),
+            EIOError::HttpStatus(502)
+        );
+    }
+
+    #[test]
+    fn test_source_errors_are_kept() {
+        let err = EIOError::from(PacketDecodeError::InvalidPacketType('9'));
+        let source = err.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<PayloadDecodeError>(),
+            Some(&PayloadDecodeError::InvalidPacket(
+                PacketDecodeError::InvalidPacketType('9')
+            ))
+        );
+        assert!(source.source().unwrap().is::<PacketDecodeError>());
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        let err = EIOError::Unreachable(Arc::new(io));
+        assert!(err.source().unwrap().is::<std::io::Error>());
+        assert_eq!(err.clone(), err);
+    }
+}
@@ -1,6 +1,21 @@
+mod builder;
 mod client;
+mod error;
 mod packet;
 mod payload;
+mod queue;
+mod reconnect;
+pub mod server;
+pub mod socketio;
+#[cfg(test)]
+mod testing;
+mod websocket;
 
-pub use client::{Client, EventHandler, Sender};
-pub use packet::PacketData;
+pub use builder::{ClientBuilder, PayloadEncoding, ProtocolVersion, Transport};
+pub use client::{Client, Delivery, DisconnectReason, EventHandler, Messages, Sender};
+pub use error::EIOError;
+pub use packet::{PacketData, PacketDecodeError};
+#[cfg(fuzzing)]
+pub use payload::Payload;
+pub use payload::PayloadDecodeError;
+pub use reconnect::ReconnectPolicy;
@@ -1,3 +1,8 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Debug, PartialEq)]
@@ -11,24 +16,26 @@ pub enum PacketType {
     Noop,
 }
 
-impl From<char> for PacketType {
-    fn from(c: char) -> Self {
+impl TryFrom<char> for PacketType {
+    type Error = PacketDecodeError;
+
+    fn try_from(c: char) -> Result<Self, PacketDecodeError> {
         use PacketType::*;
         match c {
-            '0' => Open,
-            '1' => Close,
-            '2' => Ping,
-            '3' => Pong,
-            '4' => Message,
-            '5' => Upgrade,
-            '6' => Noop,
-            _ => unreachable!(),
+            '0' => Ok(Open),
+            '1' => Ok(Close),
+            '2' => Ok(Ping),
+            '3' => Ok(Pong),
+            '4' => Ok(Message),
+            '5' => Ok(Upgrade),
+            '6' => Ok(Noop),
+            _ => Err(PacketDecodeError::InvalidPacketType(c)),
         }
     }
 }
 
 impl PacketType {
-    fn to_char(&self) -> char {
+    pub(crate) fn to_char(&self) -> char {
         use PacketType::*;
         match self {
             Open => '0',
@@ -40,6 +47,24 @@ impl PacketType {
             Noop => '6',
         }
     }
+
+    /// The packet type as a number, as it prefixes binary packets, e.g. `Message` -> 4.
+    pub(crate) fn to_byte(&self) -> u8 {
+        self.to_char() as u8 - b'0'
+    }
+}
+
+/// The data of the open packet, with which the server starts a session.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub(crate) struct OpenPacket {
+    pub(crate) sid: String,
+    pub(crate) upgrades: Vec<String>,
+    pub(crate) pingInterval: u32,
+    pub(crate) pingTimeout: u32,
+    /// Only sent in protocol version 4.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) maxPayload: Option<u64>,
 }
 
 #[derive(PartialEq)]
@@ -75,7 +100,6 @@ impl Packet {
         }
     }
 
-    #[allow(dead_code)]
     pub fn with_bytes(packet_type: PacketType, encoded_data: Vec<u8>) -> Self {
         Packet {
             packet_type,
@@ -95,40 +119,116 @@ impl Packet {
         &self.packet_type
     }
 
+    /// Encode the packet as text. Binary data is base64 encoded and
+    /// prefixed with `b`, e.g. `b4AQID`, for transports that only support text.
     pub fn encode(&self) -> String {
         match &self.encoded_data {
             PacketData::Str(string) => format!("{}{}", self.packet_type.to_char(), string),
-            _ => unimplemented!(),
+            PacketData::Bytes(bytes) => {
+                format!("b{}{}", self.packet_type.to_char(), BASE64.encode(bytes))
+            }
+        }
+    }
+
+    /// Encode a binary packet as the packet type as a number, followed by the data.
+    /// String packets are encoded as their UTF-8 text.
+    pub fn encode_bytes(&self) -> Vec<u8> {
+        match &self.encoded_data {
+            PacketData::Str(_) => self.encode().into_bytes(),
+            PacketData::Bytes(bytes) => {
+                let mut encoded = Vec::with_capacity(bytes.len() + 1);
+                encoded.push(self.packet_type.to_byte());
+                encoded.extend(bytes);
+                encoded
+            }
+        }
+    }
+
+    /// Encode the packet as text for protocol version 4, where binary
+    /// packets are messages whose data is base64 encoded and prefixed with `b`.
+    pub fn encode_v4(&self) -> String {
+        match &self.encoded_data {
+            PacketData::Str(_) => self.encode(),
+            PacketData::Bytes(bytes) => format!("b{}", BASE64.encode(bytes)),
+        }
+    }
+
+    /// Decode a packet from text of protocol version 4.
+    pub fn decode_v4(string: &str) -> Result<Self, PacketDecodeError> {
+        match string.strip_prefix('b') {
+            Some(base64) => {
+                let bytes = BASE64
+                    .decode(base64)
+                    .map_err(PacketDecodeError::InvalidBase64)?;
+                Ok(Packet::with_bytes(PacketType::Message, bytes))
+            }
+            None => Packet::from_str(string),
         }
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketDecodeError> {
+        let (&byte, data) = bytes.split_first().ok_or(PacketDecodeError::Empty)?;
         // Convert byte to char, e.g. 4u8 -> '4'
-        let packet_type = bytes
-            .iter()
-            .next()
-            .unwrap()
-            .to_string()
-            .chars()
-            .next()
-            .unwrap();
+        let packet_type = std::char::from_digit(u32::from(byte), 10)
+            .ok_or(PacketDecodeError::InvalidPacketType(char::from(byte)))?;
         Ok(Packet {
-            packet_type: packet_type.into(),
-            encoded_data: PacketData::Bytes(bytes[1..].to_owned()),
+            packet_type: PacketType::try_from(packet_type)?,
+            encoded_data: PacketData::Bytes(data.to_owned()),
         })
     }
 }
 
-#[derive(Debug)]
-pub struct PacketDecodeError {}
+/// An error decoding a packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketDecodeError {
+    /// The packet is empty, so it lacks a packet type.
+    Empty,
+    /// The packet type is none of the protocol, e.g. `'9'`.
+    InvalidPacketType(char),
+    /// The data of a binary packet is not valid base64.
+    InvalidBase64(base64::DecodeError),
+}
+
+impl fmt::Display for PacketDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketDecodeError::Empty => write!(f, "Empty packet"),
+            PacketDecodeError::InvalidPacketType(c) => write!(f, "Invalid packet type {:?}", c),
+            PacketDecodeError::InvalidBase64(err) => write!(f, "Invalid base64: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PacketDecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PacketDecodeError::InvalidBase64(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl FromStr for Packet {
     type Err = PacketDecodeError;
     fn from_str(string: &str) -> Result<Self, Self::Err> {
-        let packet_type = string.chars().next().unwrap().into();
+        // A base64 encoded binary packet, e.g. `b4AQID`
+        if let Some(encoded) = string.strip_prefix('b') {
+            let mut chars = encoded.chars();
+            let packet_type = chars.next().ok_or(PacketDecodeError::Empty)?;
+            let bytes = BASE64
+                .decode(chars.as_str())
+                .map_err(PacketDecodeError::InvalidBase64)?;
+            return Ok(Packet::with_bytes(
+                PacketType::try_from(packet_type)?,
+                bytes,
+            ));
+        }
+
+        let mut chars = string.chars();
+        let packet_type = chars.next().ok_or(PacketDecodeError::Empty)?;
         Ok(Packet {
-            packet_type,
-            encoded_data: PacketData::Str(string[1..].to_owned()),
+            packet_type: PacketType::try_from(packet_type)?,
+            encoded_data: PacketData::Str(chars.as_str().to_owned()),
         })
     }
 }
@@ -1,9 +1,13 @@
-use crate::packet::Packet;
+use crate::builder::{PayloadEncoding, ProtocolVersion};
+use crate::packet::{Packet, PacketData, PacketDecodeError};
 use std::error::Error;
-use std::fmt::Display;
+use std::fmt;
 use std::ops::Range;
 use std::str::FromStr;
 
+/// Separates the packets of a protocol version 4 payload.
+const RECORD_SEPARATOR: char = '\x1e';
+
 #[derive(Debug, PartialEq)]
 pub struct Payload {
     packets: Vec<Packet>,
@@ -29,29 +33,71 @@ impl Payload {
         }
     }
 
+    /// Create a new payload by decoding the provided bytes into packets
+    /// according to protocol version 4, where the packets are separated
+    /// by a record separator.
+    pub fn new_v4(bytes: &[u8]) -> Result<Self, PayloadDecodeError> {
+        let text = std::str::from_utf8(bytes)?;
+
+        if text.is_empty() {
+            return Ok(Payload { packets: vec![] });
+        }
+
+        let packets = text
+            .split(RECORD_SEPARATOR)
+            .map(Packet::decode_v4)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Payload { packets })
+    }
+
+    /// Decode a polling request or response body according to the protocol version.
+    pub(crate) fn decode(
+        bytes: &[u8],
+        version: ProtocolVersion,
+    ) -> Result<Self, PayloadDecodeError> {
+        match version {
+            ProtocolVersion::V3 => Self::new(bytes),
+            ProtocolVersion::V4 => Self::new_v4(bytes),
+        }
+    }
+
     pub fn from_packet(p: Packet) -> Self {
         Self { packets: vec![p] }
     }
 
-    // pub fn encode(&self) -> Vec<u8> {
-    //     let mut bytes = Vec::new();
+    pub fn from_packets(packets: Vec<Packet>) -> Self {
+        Self { packets }
+    }
 
-    //     for packet in self.packets.iter() {
-    //         let encoded_packet = packet.encode();
-    //         let mut packet_len = encoded_packet.len().to_string();
-    //         packet_len.push(':');
-    //         bytes.extend(packet_len.as_bytes().to_owned());
-    //         bytes.extend(encoded_packet.as_bytes().to_owned());
-    //     }
+    /// Encode the payload as text, where each packet is prefixed with its
+    /// length in UTF-16 code units and a colon, e.g. `6:4hello`.
+    /// Binary packets are base64 encoded.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
 
-    //     bytes
-    // }
+        for packet in self.packets.iter() {
+            let encoded_packet = packet.encode();
+            encoded.push_str(&encoded_packet.encode_utf16().count().to_string());
+            encoded.push(':');
+            encoded.push_str(&encoded_packet);
+        }
+
+        encoded
+    }
 
+    /// Encode the payload for XHR2, where each packet is prefixed with its
+    /// data type (0 for strings, 1 for binary), its length as decimal digits
+    /// and a 255 separator.
     pub fn encode_binary(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
         for packet in self.packets.iter() {
-            let encoded_packet = packet.encode();
+            let data_type = match packet.data() {
+                PacketData::Str(_) => 0,
+                PacketData::Bytes(_) => 1,
+            };
+            let encoded_packet = packet.encode_bytes();
             let mut packet_len = encoded_packet.len();
 
             let mut binary_len = Vec::new();
@@ -61,15 +107,47 @@ impl Payload {
                 packet_len /= 10;
             }
 
-            bytes.extend(&[0]);
+            bytes.extend(&[data_type]);
             bytes.extend(binary_len.iter().rev());
             bytes.extend(&[255]);
-            bytes.extend(encoded_packet.as_bytes().to_owned());
+            bytes.extend(encoded_packet);
         }
 
         bytes
     }
 
+    /// Encode the payload as text according to protocol version 4.
+    pub fn encode_v4(&self) -> String {
+        self.packets
+            .iter()
+            .map(Packet::encode_v4)
+            .collect::<Vec<_>>()
+            .join(&RECORD_SEPARATOR.to_string())
+    }
+
+    /// The number of bytes `packet` adds to a payload of `version` that is
+    /// encoded with `encoding`, including its length prefix or separator.
+    pub(crate) fn encoded_len(
+        packet: &Packet,
+        version: ProtocolVersion,
+        encoding: PayloadEncoding,
+    ) -> usize {
+        match (version, encoding) {
+            (ProtocolVersion::V3, PayloadEncoding::Binary) => {
+                let len = packet.encode_bytes().len();
+                // Data type, length digits and separator
+                1 + len.to_string().len() + 1 + len
+            }
+            (ProtocolVersion::V3, PayloadEncoding::Text) => {
+                let encoded = packet.encode();
+                // Length digits and colon
+                encoded.encode_utf16().count().to_string().len() + 1 + encoded.len()
+            }
+            (ProtocolVersion::V4, _) => packet.encode_v4().len() + RECORD_SEPARATOR.len_utf8(),
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn packets(&self) -> &Vec<Packet> {
         &self.packets
     }
@@ -81,96 +159,146 @@ impl Payload {
     fn decode_binary(mut bytes: &[u8]) -> Result<Self, PayloadDecodeError> {
         let mut packets = Vec::new();
 
-        while !bytes.is_empty() {
-            let data_type = bytes[0];
-            bytes = &bytes[1..];
-
-            let window = Self::get_next_packet_window(&bytes).unwrap();
+        while let Some((&data_type, rest)) = bytes.split_first() {
+            let window = Self::get_next_packet_window(rest)?;
             let end = window.end;
 
+            let packet_bytes = rest.get(window).ok_or(PayloadDecodeError::Truncated)?;
             if data_type == 1 {
-                let packet = Packet::from_bytes(&bytes[window]).unwrap();
-                packets.push(packet);
+                packets.push(Packet::from_bytes(packet_bytes)?);
             } else {
-                let string = String::from_utf8(bytes[window].into()).unwrap();
-                packets.push(Packet::from_str(&string).unwrap());
+                packets.push(Packet::from_str(std::str::from_utf8(packet_bytes)?)?);
             }
 
-            bytes = &bytes[end..];
+            bytes = &rest[end..];
         }
 
         Ok(Payload { packets })
     }
 
+    /// The range of the next packet in `bytes`, which start with the length of
+    /// the packet as decimal digits (one byte each) followed by a 255 separator.
+    /// The range may extend past the end of `bytes` if the payload is truncated.
     fn get_next_packet_window(bytes: &[u8]) -> Result<Range<usize>, PayloadDecodeError> {
-        let mut packet_len = 0;
-        let mut start = 0;
-        for (index, byte) in bytes.iter().enumerate() {
-            if *byte == 255 {
-                start = index + 1;
-                break;
-            } else {
-                packet_len = (10 * packet_len) + *byte as usize;
+        let separator = bytes
+            .iter()
+            .position(|byte| *byte == 255)
+            .ok_or(PayloadDecodeError::Truncated)?;
+        let digits = &bytes[..separator];
+
+        if digits.is_empty() {
+            return Err(PayloadDecodeError::InvalidLength);
+        }
+
+        let mut packet_len: usize = 0;
+        for &digit in digits {
+            if digit > 9 {
+                return Err(PayloadDecodeError::InvalidLength);
             }
+            packet_len = packet_len
+                .checked_mul(10)
+                .and_then(|len| len.checked_add(usize::from(digit)))
+                .ok_or(PayloadDecodeError::InvalidLength)?;
         }
 
-        Ok(start..packet_len + start)
+        let start = separator + 1;
+        let end = start
+            .checked_add(packet_len)
+            .ok_or(PayloadDecodeError::InvalidLength)?;
+        Ok(start..end)
     }
 
-    fn decode_text(mut bytes: &[u8]) -> Result<Self, PayloadDecodeError> {
-        let colon_index = bytes
-            .iter()
-            .position(|byte| *byte == b':')
-            .ok_or_else(|| PayloadDecodeError::new("Did not find ':'".into()))?;
-
-        let packet_len_str = String::from_utf8(bytes[..(colon_index as usize)].to_vec())
-            .map_err(|err| PayloadDecodeError::new(err.description().into()))?;
+    /// Decode a text payload, where each packet is prefixed with its length
+    /// and a colon, e.g. `6:4hello2:4a`. Like the JavaScript server, lengths
+    /// count UTF-16 code units rather than bytes.
+    fn decode_text(bytes: &[u8]) -> Result<Self, PayloadDecodeError> {
+        let mut text = std::str::from_utf8(bytes)?;
+        let mut packets = vec![];
 
-        let packet_len = packet_len_str
-            .parse::<usize>()
-            .map_err(|err| PayloadDecodeError::new(err.description().into()))?;
+        while !text.is_empty() {
+            let colon_index = text.find(':').ok_or(PayloadDecodeError::InvalidLength)?;
+            let digits = &text[..colon_index];
+            if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(PayloadDecodeError::InvalidLength);
+            }
+            let packet_len = digits
+                .parse::<usize>()
+                .map_err(|_| PayloadDecodeError::InvalidLength)?;
+
+            let rest = &text[colon_index + 1..];
+            let end = Self::utf16_index(rest, packet_len)?;
+            packets.push(Packet::from_str(&rest[..end])?);
+            text = &rest[end..];
+        }
 
-        let mut end = 0;
-        let mut packets = vec![];
+        Ok(Payload { packets })
+    }
 
-        while end < bytes.len() + 1 {
-            end = colon_index as usize + 1 + packet_len;
-            let packet_bytes = bytes[colon_index as usize + 1..end].to_owned();
-            let packet_str = String::from_utf8(packet_bytes)
-                .map_err(|err| PayloadDecodeError::new(err.description().into()))?;
-            let packet = Packet::from_str(&packet_str).unwrap();
-            // .map_err(|err| PayloadDecodeError::new(err.description().into()))?;
+    /// The byte index of `text` after the first `len` UTF-16 code units.
+    fn utf16_index(text: &str, len: usize) -> Result<usize, PayloadDecodeError> {
+        let mut units = 0;
+        for (index, c) in text.char_indices() {
+            if units == len {
+                return Ok(index);
+            }
+            units += c.len_utf16();
+            // The length ends within a surrogate pair
+            if units > len {
+                return Err(PayloadDecodeError::InvalidLength);
+            }
+        }
 
-            packets.push(packet);
-            bytes = &bytes[end..];
+        if units == len {
+            Ok(text.len())
+        } else {
+            Err(PayloadDecodeError::Truncated)
         }
-        Ok(Payload { packets })
     }
 }
 
-#[derive(Debug, PartialEq, Default)]
-pub struct PayloadDecodeError {
-    description: String,
+/// An error decoding a payload, i.e. the packets of a polling request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayloadDecodeError {
+    /// The length prefix of a packet is missing or not a number.
+    InvalidLength,
+    /// The payload ends before the length of a packet.
+    Truncated,
+    /// A text packet is not valid UTF-8.
+    InvalidUtf8(std::str::Utf8Error),
+    /// A packet of the payload is invalid.
+    InvalidPacket(PacketDecodeError),
 }
 
-impl PayloadDecodeError {
-    pub fn new(description: String) -> Self {
-        PayloadDecodeError { description }
+impl fmt::Display for PayloadDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadDecodeError::InvalidLength => write!(f, "Invalid length prefix"),
+            PayloadDecodeError::Truncated => write!(f, "Truncated payload"),
+            PayloadDecodeError::InvalidUtf8(err) => write!(f, "Invalid UTF-8: {}", err),
+            PayloadDecodeError::InvalidPacket(err) => write!(f, "Invalid packet: {}", err),
+        }
     }
 }
 
-impl Display for PayloadDecodeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.description)
+impl Error for PayloadDecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PayloadDecodeError::InvalidUtf8(err) => Some(err),
+            PayloadDecodeError::InvalidPacket(err) => Some(err),
+            _ => None,
+        }
     }
 }
 
-impl Error for PayloadDecodeError {}
+impl From<std::str::Utf8Error> for PayloadDecodeError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        PayloadDecodeError::InvalidUtf8(err)
+    }
+}
 
-impl From<std::num::ParseIntError> for PayloadDecodeError {
-    fn from(error: std::num::ParseIntError) -> Self {
-        log::error!("{:#?}", error);
-        unimplemented!();
+impl From<PacketDecodeError> for PayloadDecodeError {
+    fn from(err: PacketDecodeError) -> Self {
+        PayloadDecodeError::InvalidPacket(err)
     }
 }
 
@@ -178,14 +306,18 @@ impl From<std::num::ParseIntError> for PayloadDecodeError {
 mod tests {
     use super::*;
     use crate::packet::PacketType;
+    use proptest::prelude::*;
+    use std::convert::TryFrom;
 
     #[test]
     fn test_payload_decoding_of_one_packet() {
         let input = r#"96:0{"sid":"d5vWJMbJuMCRZOnuAAAI","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#;
 
-        let result = Payload::new(&input.as_bytes()).unwrap();
-        let expected = Packet::with_str( PacketType::Open,
-             r#"{"sid":"d5vWJMbJuMCRZOnuAAAI","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#);
+        let result = Payload::new(input.as_bytes()).unwrap();
+        let expected = Packet::with_str(
+            PacketType::Open,
+            r#"{"sid":"d5vWJMbJuMCRZOnuAAAI","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#,
+        );
 
         assert_eq!(*result.packets.first().unwrap(), expected);
     }
@@ -195,9 +327,11 @@ mod tests {
         let mut input = r#"96:0{"sid":"d5vWJMbJuMCRZOnuAAAI","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#.to_owned();
         input.push_str(&input.clone());
         input.push_str(&input.clone());
-        let result = Payload::new(&input.as_bytes()).unwrap();
-        let expected = Packet::with_str( PacketType::Open,
-            r#"{"sid":"d5vWJMbJuMCRZOnuAAAI","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#);
+        let result = Payload::new(input.as_bytes()).unwrap();
+        let expected = Packet::with_str(
+            PacketType::Open,
+            r#"{"sid":"d5vWJMbJuMCRZOnuAAAI","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":5000}"#,
+        );
 
         let mut iter_count = 0;
         for packet in result.packets {
@@ -213,6 +347,58 @@ mod tests {
         assert_eq!(p.packets()[0], Packet::with_str(PacketType::Message, "abc"));
     }
 
+    #[test]
+    fn test_text_decoding_of_packets_of_different_lengths() {
+        let payload = Payload::new(b"6:4hello2:4a1:2").unwrap();
+
+        assert_eq!(
+            payload.packets(),
+            &vec![
+                Packet::with_str(PacketType::Message, "hello"),
+                Packet::with_str(PacketType::Message, "a"),
+                Packet::with_str(PacketType::Ping, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_decoding_counts_utf16_code_units() {
+        // "é" is one code unit in two bytes, "😀" two code units in four bytes
+        let payload = Payload::new("5:4café4:4😀!2:4x".as_bytes()).unwrap();
+
+        assert_eq!(
+            payload.packets(),
+            &vec![
+                Packet::with_str(PacketType::Message, "café"),
+                Packet::with_str(PacketType::Message, "😀!"),
+                Packet::with_str(PacketType::Message, "x"),
+            ]
+        );
+
+        // The length ends within the surrogate pair of "😀"
+        assert_eq!(
+            Payload::new("2:4😀".as_bytes()),
+            Err(PayloadDecodeError::InvalidLength)
+        );
+        assert_eq!(
+            Payload::new("7:4café".as_bytes()),
+            Err(PayloadDecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_text_encoding() {
+        let payload = Payload::from_packets(vec![
+            Packet::with_str(PacketType::Message, "héllo 😀"),
+            Packet::with_bytes(PacketType::Message, vec![1, 2, 3]),
+            Packet::with_str(PacketType::Ping, ""),
+        ]);
+        let encoded = payload.encode();
+
+        assert_eq!(encoded, "9:4héllo 😀6:b4AQID1:2");
+        assert_eq!(Payload::new(encoded.as_bytes()).unwrap(), payload);
+    }
+
     #[test]
     fn test_xhr2_encoding() {
         let binary = Payload::new(b"8:4message").unwrap().encode_binary();
@@ -250,6 +436,62 @@ mod tests {
         assert_eq!(*second_packet, expected_second_packet);
     }
 
+    #[test]
+    fn test_encoded_len_of_packets() {
+        let packets = || {
+            vec![
+                Packet::with_str(PacketType::Message, "hello world"),
+                Packet::with_bytes(PacketType::Message, vec![1, 2, 3]),
+            ]
+        };
+        let len = |version, encoding| {
+            packets()
+                .iter()
+                .map(|packet| Payload::encoded_len(packet, version, encoding))
+                .sum::<usize>()
+        };
+        let payload = Payload::from_packets(packets());
+
+        assert_eq!(
+            len(ProtocolVersion::V3, PayloadEncoding::Binary),
+            payload.encode_binary().len()
+        );
+        assert_eq!(
+            len(ProtocolVersion::V3, PayloadEncoding::Text),
+            payload.encode().len()
+        );
+        assert_eq!(
+            len(ProtocolVersion::V4, PayloadEncoding::Binary),
+            payload.encode_v4().len() + 1
+        );
+    }
+
+    #[test]
+    fn test_xhr2_encoding_of_binary_packet() {
+        let payload = Payload::from_packet(Packet::with_bytes(PacketType::Message, vec![1, 2, 3]));
+        let encoded = payload.encode_binary();
+
+        assert_eq!(encoded, vec![1, 4, 255, 4, 1, 2, 3]);
+        assert_eq!(Payload::new(&encoded).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_base64_text_encoding_of_binary_packet() {
+        let packet = Packet::with_bytes(PacketType::Message, vec![1, 2, 3]);
+        assert_eq!(packet.encode(), "b4AQID");
+    }
+
+    #[test]
+    fn test_base64_text_decoding_of_binary_packet() {
+        let payload = Payload::new(b"6:b4AQID").unwrap();
+        assert_eq!(
+            payload.packets()[0],
+            Packet::with_bytes(PacketType::Message, vec![1, 2, 3])
+        );
+
+        assert!(Payload::new(b"6:b4A!ID").is_err());
+    }
+
     #[test]
     fn test_multi_binary_payload() {
         let mut bytes = vec![0, 4, 255];
@@ -272,15 +514,141 @@ mod tests {
         assert_eq!(payload.encode_binary(), bytes);
     }
 
+    #[test]
+    fn test_v4_payload_decoding() {
+        let payload = Payload::new_v4(b"4hello\x1ebAQID\x1e2").unwrap();
+
+        assert_eq!(
+            payload.packets(),
+            &vec![
+                Packet::with_str(PacketType::Message, "hello"),
+                Packet::with_bytes(PacketType::Message, vec![1, 2, 3]),
+                Packet::with_str(PacketType::Ping, ""),
+            ]
+        );
+        assert_eq!(payload.encode_v4(), "4hello\x1ebAQID\x1e2");
+    }
+
+    #[test]
+    fn test_v4_empty_payload() {
+        assert!(Payload::new_v4(b"").unwrap().packets().is_empty());
+        assert!(Payload::new_v4(b"bnot base64!").is_err());
+    }
+
     #[test]
     fn test_invalid_input_returns_error() {
         let bytes = vec![3, 4, 255];
         let payload_err = Payload::new(&bytes).unwrap_err();
+        assert!(matches!(payload_err, PayloadDecodeError::InvalidUtf8(_)));
+    }
+
+    #[test]
+    fn test_decode_errors_tell_the_cause() {
         assert_eq!(
-            payload_err,
-            PayloadDecodeError {
-                description: "Did not find ':'".into(),
-            }
+            Payload::new(b"2:9x"),
+            Err(PayloadDecodeError::InvalidPacket(
+                PacketDecodeError::InvalidPacketType('9')
+            ))
+        );
+        assert_eq!(
+            Payload::new(b"x:4hello"),
+            Err(PayloadDecodeError::InvalidLength)
+        );
+        assert_eq!(
+            Payload::new(&[0, 9, 255, b'4']),
+            Err(PayloadDecodeError::Truncated)
+        );
+        assert!(matches!(
+            Payload::new(&[0, 2, 255, b'4', 0xff]),
+            Err(PayloadDecodeError::InvalidUtf8(_))
+        ));
+        assert!(matches!(
+            Payload::new_v4(b"bnot base64!"),
+            Err(PayloadDecodeError::InvalidPacket(
+                PacketDecodeError::InvalidBase64(_)
+            ))
+        ));
+        assert_eq!(
+            Payload::new_v4(b"4hello\x1e"),
+            Err(PayloadDecodeError::InvalidPacket(PacketDecodeError::Empty))
+        );
+    }
+
+    #[test]
+    fn test_invalid_binary_framing_returns_error() {
+        // Length digits must be at most 9
+        assert_eq!(
+            Payload::new(&[0, 1, 10, 255, b'4']),
+            Err(PayloadDecodeError::InvalidLength)
+        );
+        // Missing length
+        assert_eq!(
+            Payload::new(&[0, 255, b'4']),
+            Err(PayloadDecodeError::InvalidLength)
+        );
+        // Missing separator
+        assert_eq!(Payload::new(&[1, 4]), Err(PayloadDecodeError::Truncated));
+        // Length overflows
+        let mut bytes = vec![0; 1];
+        bytes.extend(vec![9; 30]);
+        bytes.push(255);
+        assert_eq!(Payload::new(&bytes), Err(PayloadDecodeError::InvalidLength));
+        assert_eq!(
+            Payload::new(b"99999999999999999999999:4"),
+            Err(PayloadDecodeError::InvalidLength)
         );
     }
+
+    fn packet(v4: bool) -> impl Strategy<Value = Packet> {
+        let packet_type = (0..=6u32)
+            .prop_map(|n| PacketType::try_from(std::char::from_digit(n, 10).unwrap()).unwrap());
+        let data = prop_oneof![
+            "[^\x1e]*".prop_map(PacketData::Str),
+            any::<Vec<u8>>().prop_map(PacketData::Bytes),
+        ];
+        (packet_type, data).prop_map(move |(packet_type, data)| match data {
+            // Binary packets of version 4 are always messages
+            PacketData::Bytes(bytes) if v4 => Packet::with_bytes(PacketType::Message, bytes),
+            data => Packet::new(packet_type, data),
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_decoding_never_panics(bytes in any::<Vec<u8>>()) {
+            let _ = Payload::new(&bytes);
+            let _ = Payload::new_v4(&bytes);
+        }
+
+        #[test]
+        fn test_decoding_of_binary_framing_never_panics(
+            data_type in 0..=1u8,
+            digits in prop::collection::vec(0..=12u8, 0..25),
+            rest in any::<Vec<u8>>(),
+        ) {
+            let mut bytes = vec![data_type];
+            bytes.extend(digits);
+            bytes.push(255);
+            bytes.extend(rest);
+            let _ = Payload::new(&bytes);
+        }
+
+        #[test]
+        fn test_binary_encoding_roundtrip(packets in prop::collection::vec(packet(false), 1..5)) {
+            let payload = Payload::from_packets(packets);
+            prop_assert_eq!(Payload::new(&payload.encode_binary()).unwrap(), payload);
+        }
+
+        #[test]
+        fn test_text_encoding_roundtrip(packets in prop::collection::vec(packet(false), 1..5)) {
+            let payload = Payload::from_packets(packets);
+            prop_assert_eq!(Payload::new(payload.encode().as_bytes()).unwrap(), payload);
+        }
+
+        #[test]
+        fn test_v4_encoding_roundtrip(packets in prop::collection::vec(packet(true), 1..5)) {
+            let payload = Payload::from_packets(packets);
+            prop_assert_eq!(Payload::new_v4(payload.encode_v4().as_bytes()).unwrap(), payload);
+        }
+    }
 }
@@ -0,0 +1,237 @@
+use crate::error::EIOError;
+use crate::packet::Packet;
+use futures::channel::{mpsc, oneshot};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{FusedStream, Stream, StreamExt};
+use futures::task::{Context, Poll};
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Create the bounded queue of packets to be written to the server.
+///
+/// The queue holds `capacity` packets, plus one for every sender,
+/// after which sending waits until the connection wrote a packet.
+pub(crate) fn queue(capacity: usize) -> (QueueSender, QueueReceiver) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let depth = Arc::new(AtomicUsize::new(0));
+
+    (
+        QueueSender {
+            sender,
+            depth: Arc::clone(&depth),
+        },
+        QueueReceiver { receiver, depth },
+    )
+}
+
+/// A packet in the write queue, with the channel to report
+/// the result of writing it, if the sender is waiting for it.
+pub(crate) struct Queued {
+    pub(crate) packet: Packet,
+    pub(crate) confirmation: Confirmation,
+}
+
+/// Reports the result of writing a queued packet to its sender.
+pub(crate) struct Confirmation(Option<oneshot::Sender<Result<(), EIOError>>>);
+
+impl Confirmation {
+    pub(crate) fn confirm(self, result: Result<(), EIOError>) {
+        if let Some(sender) = self.0 {
+            // The sender may not wait for the result anymore
+            let _ = sender.send(result);
+        }
+    }
+}
+
+/// The sending half of a write queue, which counts the packets in the queue.
+#[derive(Clone)]
+pub(crate) struct QueueSender {
+    sender: mpsc::Sender<Queued>,
+    depth: Arc<AtomicUsize>,
+}
+
+/// The receiving half of a write queue, from which the connection takes
+/// the packets to write.
+pub(crate) struct QueueReceiver {
+    receiver: mpsc::Receiver<Queued>,
+    depth: Arc<AtomicUsize>,
+}
+
+fn closed(_: mpsc::SendError) -> EIOError {
+    EIOError::Closed
+}
+
+impl QueueSender {
+    /// Queue a packet, waiting for capacity if the queue is full.
+    pub(crate) async fn send(&mut self, packet: Packet) -> Result<(), EIOError> {
+        SinkExt::send(self, packet).await
+    }
+
+    /// Queue a packet like [`send`](#method.send), and return
+    /// the channel that reports the result of writing it.
+    pub(crate) async fn send_confirmed(
+        &mut self,
+        packet: Packet,
+    ) -> Result<oneshot::Receiver<Result<(), EIOError>>, EIOError> {
+        let (sender, receiver) = oneshot::channel();
+        futures::future::poll_fn(|cx| self.poll_ready_unpin(cx)).await?;
+        self.start(Queued {
+            packet,
+            confirmation: Confirmation(Some(sender)),
+        })?;
+        Ok(receiver)
+    }
+
+    /// Queue a packet, or fail with `EIOError::QueueFull` if the queue is full.
+    pub(crate) fn try_send(&mut self, packet: Packet) -> Result<(), EIOError> {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        let queued = Queued {
+            packet,
+            confirmation: Confirmation(None),
+        };
+        self.sender.try_send(queued).map_err(|err| {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            if err.is_full() {
+                EIOError::QueueFull
+            } else {
+                closed(err.into_send_error())
+            }
+        })
+    }
+
+    /// The number of packets in the queue.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Whether the connection stopped taking packets from the queue.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Queue a packet once `poll_ready` reported capacity.
+    fn start(&mut self, queued: Queued) -> Result<(), EIOError> {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        self.sender.start_send(queued).map_err(|err| {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            closed(err)
+        })
+    }
+}
+
+impl Sink<Packet> for QueueSender {
+    type Error = EIOError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), EIOError>> {
+        self.sender.poll_ready(cx).map_err(closed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: Packet) -> Result<(), EIOError> {
+        self.start(Queued {
+            packet,
+            confirmation: Confirmation(None),
+        })
+    }
+
+    /// Queued packets need no flushing. The flush of the channel would
+    /// instead wait until the next packet fits into the queue.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), EIOError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), EIOError>> {
+        Pin::new(&mut self.sender).poll_close(cx).map_err(closed)
+    }
+}
+
+impl QueueReceiver {
+    /// Take the next packet if one is queued, without waiting.
+    pub(crate) fn try_recv(&mut self) -> Option<Queued> {
+        let queued = self.receiver.try_recv().ok()?;
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        Some(queued)
+    }
+}
+
+impl Stream for QueueReceiver {
+    type Item = Queued;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Queued>> {
+        let next = self.receiver.poll_next_unpin(cx);
+        if let Poll::Ready(Some(_)) = next {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        next
+    }
+}
+
+impl FusedStream for QueueReceiver {
+    fn is_terminated(&self) -> bool {
+        self.receiver.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketType;
+    use async_std::future;
+    use std::time::Duration;
+
+    fn message() -> Packet {
+        Packet::with_str(PacketType::Message, "hello")
+    }
+
+    #[async_std::test]
+    async fn test_depth_and_capacity() {
+        let (mut sender, mut receiver) = queue(1);
+
+        sender.send(message()).await.unwrap();
+        // One more packet fits into the slot of the sender
+        sender.try_send(message()).unwrap();
+        assert_eq!(sender.depth(), 2);
+        assert!(matches!(
+            sender.try_send(message()),
+            Err(EIOError::QueueFull)
+        ));
+        assert_eq!(sender.depth(), 2);
+
+        assert_eq!(receiver.next().await.map(|q| q.packet), Some(message()));
+        assert_eq!(sender.depth(), 1);
+        sender.try_send(message()).unwrap();
+        assert_eq!(sender.depth(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_send_waits_for_capacity() {
+        let (mut sender, mut receiver) = queue(1);
+        sender.send(message()).await.unwrap();
+        sender.send(message()).await.unwrap();
+
+        let timeout = Duration::from_millis(20);
+        assert!(future::timeout(timeout, sender.send(message()))
+            .await
+            .is_err());
+        assert_eq!(sender.depth(), 2);
+
+        receiver.next().await.unwrap();
+        sender.send(message()).await.unwrap();
+
+        drop(receiver);
+        assert!(matches!(sender.try_send(message()), Err(EIOError::Closed)));
+    }
+
+    #[async_std::test]
+    async fn test_confirmed_send() {
+        let (mut sender, mut receiver) = queue(1);
+        let delivered = sender.send_confirmed(message()).await.unwrap();
+        let dropped = sender.send_confirmed(message()).await.unwrap();
+
+        receiver.next().await.unwrap().confirmation.confirm(Ok(()));
+        assert!(matches!(delivered.await, Ok(Ok(()))));
+        drop(receiver);
+        assert!(dropped.await.is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Determines if and how a client reconnects after it lost the
+/// connection to the server.
+///
+/// The delay before each attempt grows exponentially from `base_delay`
+/// up to `max_delay` and is randomized by `randomization_factor`.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The maximum number of consecutive attempts, or `None` for no limit.
+    pub max_attempts: Option<u32>,
+    /// The delay before the first attempt, doubled with every further attempt.
+    pub base_delay: Duration,
+    /// The upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+    /// The jitter applied to each delay, as a fraction between 0 and 1.
+    /// A factor of 0.5 turns a delay of 2s into a delay between 1s and 3s,
+    /// so that many clients don't hit a recovering server at once.
+    pub randomization_factor: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: None,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            randomization_factor: 0.5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// A policy that never reconnects.
+    pub fn disabled() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the given attempt, counting from 0, may be made.
+    pub(crate) fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt < max)
+    }
+
+    /// The delay before the given attempt, counting from 0.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay.as_millis() as f64;
+        let max = self.max_delay.as_millis() as f64;
+        let delay = (base * 2f64.powi(attempt.min(32) as i32)).min(max);
+
+        let factor = self.randomization_factor.clamp(0.0, 1.0);
+        let jitter = delay * factor * rand::thread_rng().gen_range(-1.0..=1.0);
+
+        Duration::from_millis((delay + jitter).clamp(0.0, max) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(randomization_factor: f64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(3),
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            randomization_factor,
+        }
+    }
+
+    #[test]
+    fn test_delay_grows_exponentially_up_to_max() {
+        let policy = policy(0.0);
+
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(800));
+        assert_eq!(policy.delay(4), Duration::from_millis(1000));
+        assert_eq!(policy.delay(100), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_delay_is_randomized_within_factor() {
+        let policy = policy(0.5);
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = policy(0.0);
+
+        assert!(policy.allows(2));
+        assert!(!policy.allows(3));
+        assert!(!ReconnectPolicy::disabled().allows(0));
+        assert!(ReconnectPolicy::default().allows(u32::MAX));
+    }
+}
@@ -0,0 +1,736 @@
+use crate::builder::ProtocolVersion;
+use crate::packet::{OpenPacket, Packet, PacketData, PacketType};
+use crate::payload::Payload;
+use async_std::io::{self, ReadExt};
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_std::sync::Mutex as AsyncMutex;
+use async_std::task;
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use http_types::{Method, Mime, Request, Response, StatusCode};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+
+/// Handles the events of a single client session on the server,
+/// the server-side counterpart of the client's `EventHandler`.
+#[async_trait]
+pub trait ServerEventHandler {
+    /// Called when the session is established, with the socket
+    /// that can be used to emit messages to this client.
+    async fn on_connect(&mut self, socket: Socket);
+
+    async fn on_disconnect(&mut self);
+
+    async fn on_message(&mut self, data: PacketData);
+}
+
+/// A handle to a client session, which can be used to emit
+/// messages to that client or to close the session.
+#[derive(Clone)]
+pub struct Socket {
+    sid: String,
+    outgoing: mpsc::UnboundedSender<Packet>,
+    incoming: mpsc::UnboundedSender<Packet>,
+}
+
+impl Socket {
+    /// The id of the session.
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    pub async fn emit_str(&mut self, data: String) {
+        self.emit(PacketData::Str(data)).await;
+    }
+
+    pub async fn emit_bytes(&mut self, data: Vec<u8>) {
+        self.emit(PacketData::Bytes(data)).await;
+    }
+
+    /// Queue a message, which is delivered with the next poll of the client.
+    /// Messages emitted after the session is closed are dropped.
+    pub async fn emit(&mut self, data: PacketData) {
+        debug!("Emitting {:?} to {}", data, self.sid);
+        let _ = self
+            .outgoing
+            .send(Packet::new(PacketType::Message, data))
+            .await;
+    }
+
+    /// Close the session, which sends a close packet to the client.
+    pub fn close(&self) {
+        self.incoming.close_channel();
+    }
+}
+
+/// The server side state of a client session.
+struct Session {
+    version: ProtocolVersion,
+    /// Packets from the client, handled by the session task.
+    incoming: mpsc::UnboundedSender<Packet>,
+    /// Packets for the client, delivered by the next poll.
+    outgoing: AsyncMutex<mpsc::UnboundedReceiver<Packet>>,
+}
+
+type Sessions = Mutex<FnvHashMap<String, Arc<Session>>>;
+
+/// The maximum size of a posted payload, as advertised to version 4 clients.
+const MAX_PAYLOAD: u64 = 1_000_000;
+
+/// An engine.io server speaking the polling transport.
+///
+/// Every client session gets its own handler, created by the
+/// `new_handler` function the server is constructed with.
+pub struct Server<F> {
+    new_handler: F,
+    path: String,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    sessions: Arc<Sessions>,
+}
+
+/// The engine.io error codes sent to clients with an HTTP 400 status.
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    UnknownTransport = 0,
+    UnknownSid = 1,
+    BadHandshakeMethod = 2,
+    BadRequest = 3,
+    UnsupportedProtocolVersion = 5,
+}
+
+impl ErrorCode {
+    fn message(self) -> &'static str {
+        match self {
+            ErrorCode::UnknownTransport => "Transport unknown",
+            ErrorCode::UnknownSid => "Session ID unknown",
+            ErrorCode::BadHandshakeMethod => "Bad handshake method",
+            ErrorCode::BadRequest => "Bad request",
+            ErrorCode::UnsupportedProtocolVersion => "Unsupported protocol version",
+        }
+    }
+
+    fn into_response(self) -> Response {
+        let mut response = Response::new(StatusCode::BadRequest);
+        response.set_content_type(http_types::mime::JSON);
+        response.set_body(
+            serde_json::json!({ "code": self as u8, "message": self.message() }).to_string(),
+        );
+        response
+    }
+}
+
+impl<F, H> Server<F>
+where
+    F: Fn() -> H + Send + Sync + 'static,
+    H: ServerEventHandler + Send + Sync + 'static,
+{
+    /// Create a server that serves `/engine.io/`, and calls `new_handler`
+    /// to create the handler of every new session.
+    pub fn new(new_handler: F) -> Self {
+        Server {
+            new_handler,
+            path: "/engine.io/".into(),
+            ping_interval: Duration::from_millis(25000),
+            ping_timeout: Duration::from_millis(5000),
+            sessions: Arc::new(Mutex::new(FnvHashMap::default())),
+        }
+    }
+
+    /// The path under which the server is reachable.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_owned();
+        self
+    }
+
+    /// The interval in which heartbeats are exchanged.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// The time after which a missing heartbeat closes the session.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Bind to `addr` and serve clients until an error occurs.
+    pub async fn listen(self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve the clients connecting to `listener`.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        info!("Listening on {}", listener.local_addr()?);
+        let server = Arc::new(self);
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            let stream = stream?;
+            let server = Arc::clone(&server);
+            task::spawn(async move {
+                if let Err(err) = server.accept(stream).await {
+                    debug!("Connection closed with error: {}", err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn accept(self: Arc<Self>, stream: TcpStream) -> http_types::Result<()> {
+        async_h1::accept(stream, |request| {
+            let server = Arc::clone(&self);
+            async move { Ok(server.handle(request).await) }
+        })
+        .await
+    }
+
+    /// Handle a single HTTP request of the polling transport, so that
+    /// the server can be mounted into another HTTP server.
+    pub async fn handle(&self, mut request: Request) -> Response {
+        if request.url().path() != self.path {
+            return Response::new(StatusCode::NotFound);
+        }
+
+        let mut version = None;
+        let mut transport = None;
+        let mut sid = None;
+        let mut base64 = false;
+        for (key, value) in request.url().query_pairs() {
+            match key.as_ref() {
+                "EIO" => version = Some(value.into_owned()),
+                "transport" => transport = Some(value.into_owned()),
+                "sid" => sid = Some(value.into_owned()),
+                // Clients that cannot receive binary data ask for text payloads
+                "b64" => base64 = value == "1",
+                _ => (),
+            }
+        }
+
+        let version = match version.as_deref() {
+            Some("3") => ProtocolVersion::V3,
+            Some("4") => ProtocolVersion::V4,
+            _ => return ErrorCode::UnsupportedProtocolVersion.into_response(),
+        };
+
+        if transport.as_deref() != Some("polling") {
+            return ErrorCode::UnknownTransport.into_response();
+        }
+
+        let sid = match sid {
+            Some(sid) => sid,
+            None if request.method() == Method::Get => return self.handshake(version, base64),
+            None => return ErrorCode::BadHandshakeMethod.into_response(),
+        };
+
+        let session = self.sessions.lock().unwrap().get(&sid).cloned();
+        let session = match session {
+            Some(session) => session,
+            None => return ErrorCode::UnknownSid.into_response(),
+        };
+
+        match request.method() {
+            Method::Get => Self::poll(&session, base64).await,
+            Method::Post => match Self::read_body(&mut request).await {
+                Ok(Some(body)) => Self::receive(&session, &body),
+                Ok(None) => Response::new(StatusCode::PayloadTooLarge),
+                Err(_) => ErrorCode::BadRequest.into_response(),
+            },
+            _ => ErrorCode::BadRequest.into_response(),
+        }
+    }
+
+    /// Read the body of a request, or `None` if it exceeds `MAX_PAYLOAD`.
+    async fn read_body(request: &mut Request) -> io::Result<Option<Vec<u8>>> {
+        if request.len().is_some_and(|len| len as u64 > MAX_PAYLOAD) {
+            return Ok(None);
+        }
+        // The length may be unknown, so read one byte more to tell
+        let mut body = Vec::new();
+        request
+            .take_body()
+            .take(MAX_PAYLOAD + 1)
+            .read_to_end(&mut body)
+            .await?;
+        Ok(Some(body).filter(|body| body.len() as u64 <= MAX_PAYLOAD))
+    }
+
+    /// Start a new session and spawn the task that drives it.
+    fn handshake(&self, version: ProtocolVersion, base64: bool) -> Response {
+        let sid: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(20)
+            .map(char::from)
+            .collect();
+
+        let (incoming_sender, incoming_receiver) = mpsc::unbounded();
+        let (outgoing_sender, outgoing_receiver) = mpsc::unbounded();
+        let session = Arc::new(Session {
+            version,
+            incoming: incoming_sender.clone(),
+            outgoing: AsyncMutex::new(outgoing_receiver),
+        });
+        self.sessions.lock().unwrap().insert(sid.clone(), session);
+
+        let socket = Socket {
+            sid: sid.clone(),
+            outgoing: outgoing_sender,
+            incoming: incoming_sender,
+        };
+        let heartbeat = Heartbeat {
+            version,
+            ping_interval: self.ping_interval,
+            ping_timeout: self.ping_timeout,
+        };
+        task::spawn(run_session(
+            socket,
+            (self.new_handler)(),
+            incoming_receiver,
+            heartbeat,
+            Arc::clone(&self.sessions),
+        ));
+
+        info!("New session {}", sid);
+        let open = OpenPacket {
+            sid,
+            upgrades: vec![],
+            pingInterval: self.ping_interval.as_millis() as u32,
+            pingTimeout: self.ping_timeout.as_millis() as u32,
+            maxPayload: match version {
+                ProtocolVersion::V3 => None,
+                ProtocolVersion::V4 => Some(MAX_PAYLOAD),
+            },
+        };
+        let open = serde_json::to_string(&open).expect("open packet is serializable");
+        Self::respond(
+            version,
+            base64,
+            Payload::from_packet(Packet::with_str(PacketType::Open, &open)),
+        )
+    }
+
+    /// Answer a long poll with all queued packets, once there is at least one.
+    async fn poll(session: &Session, base64: bool) -> Response {
+        let mut outgoing = match session.outgoing.try_lock() {
+            Some(outgoing) => outgoing,
+            // Only one poll may be pending per session
+            None => return ErrorCode::BadRequest.into_response(),
+        };
+
+        let mut packets = match outgoing.next().await {
+            Some(packet) => vec![packet],
+            None => vec![Packet::with_str(PacketType::Noop, "")],
+        };
+        while let Ok(packet) = outgoing.try_recv() {
+            packets.push(packet);
+        }
+
+        Self::respond(session.version, base64, Payload::from_packets(packets))
+    }
+
+    /// Forward the packets posted by the client to the session task.
+    fn receive(session: &Session, body: &[u8]) -> Response {
+        let payload = match Payload::decode(body, session.version) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("Could not decode payload: {}", err);
+                return ErrorCode::BadRequest.into_response();
+            }
+        };
+
+        for packet in payload.into_packets() {
+            let _ = session.incoming.unbounded_send(packet);
+        }
+
+        let mut response = Response::new(StatusCode::Ok);
+        response.set_content_type(http_types::mime::PLAIN);
+        response.set_body("ok");
+        response
+    }
+
+    /// Answer with `payload`, which is encoded as text with base64 encoded
+    /// binary data in version 3 if the client asked for it with `b64=1`.
+    fn respond(version: ProtocolVersion, base64: bool, payload: Payload) -> Response {
+        let mut response = Response::new(StatusCode::Ok);
+        match version {
+            ProtocolVersion::V3 if base64 => {
+                response.set_content_type(http_types::mime::PLAIN);
+                response.set_body(payload.encode());
+            }
+            ProtocolVersion::V3 => {
+                response.set_content_type(Mime::from("application/octet-stream"));
+                response.set_body(payload.encode_binary());
+            }
+            ProtocolVersion::V4 => {
+                response.set_content_type(http_types::mime::PLAIN);
+                response.set_body(payload.encode_v4());
+            }
+        }
+        response
+    }
+}
+
+/// The heartbeat settings of a session.
+struct Heartbeat {
+    version: ProtocolVersion,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+}
+
+/// Handle the packets of a session until the client closes it, the socket
+/// is closed or the heartbeat times out.
+///
+/// In protocol version 3 the client pings and we answer with a pong, while
+/// in version 4 we ping the client every `ping_interval` and expect a pong.
+async fn run_session(
+    socket: Socket,
+    mut event_handler: impl ServerEventHandler + Send + Sync,
+    mut incoming: mpsc::UnboundedReceiver<Packet>,
+    heartbeat: Heartbeat,
+    sessions: Arc<Sessions>,
+) {
+    event_handler.on_connect(socket.clone()).await;
+
+    let mut awaiting_pong = false;
+    let mut deadline = Instant::now()
+        + match heartbeat.version {
+            ProtocolVersion::V3 => heartbeat.ping_interval + heartbeat.ping_timeout,
+            ProtocolVersion::V4 => heartbeat.ping_interval,
+        };
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let packet = match async_std::future::timeout(remaining, incoming.next()).await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => {
+                debug!("Session {} closed by server", socket.sid);
+                break;
+            }
+            Err(_) if heartbeat.version == ProtocolVersion::V4 && !awaiting_pong => {
+                let _ = socket
+                    .outgoing
+                    .unbounded_send(Packet::with_str(PacketType::Ping, ""));
+                awaiting_pong = true;
+                deadline = Instant::now() + heartbeat.ping_timeout;
+                continue;
+            }
+            Err(_) => {
+                info!("Session {} timed out", socket.sid);
+                break;
+            }
+        };
+
+        debug!("Received {:?} from {}", packet, socket.sid);
+        match packet.packet_type() {
+            PacketType::Ping if heartbeat.version == ProtocolVersion::V3 => {
+                let _ = socket
+                    .outgoing
+                    .unbounded_send(Packet::new(PacketType::Pong, packet.into_data()));
+                deadline = Instant::now() + heartbeat.ping_interval + heartbeat.ping_timeout;
+            }
+            PacketType::Pong if heartbeat.version == ProtocolVersion::V4 => {
+                awaiting_pong = false;
+                deadline = Instant::now() + heartbeat.ping_interval;
+            }
+            PacketType::Message => {
+                event_handler.on_message(packet.into_data()).await;
+            }
+            PacketType::Close => {
+                debug!("Session {} closed by client", socket.sid);
+                break;
+            }
+            PacketType::Noop => (),
+            _ => {
+                error!("Unexpected packet {:?}", packet);
+            }
+        }
+    }
+
+    sessions.lock().unwrap().remove(&socket.sid);
+    // Answer a pending poll and drop everything emitted from now on
+    let _ = socket
+        .outgoing
+        .unbounded_send(Packet::with_str(PacketType::Close, ""));
+    socket.outgoing.close_channel();
+    socket.incoming.close_channel();
+
+    event_handler.on_disconnect().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{PayloadEncoding, Transport};
+    use crate::client::Client;
+    use crate::testing::{self, RecordingHandler};
+    use http_types::Body;
+
+    /// Echoes every message back to the client.
+    struct EchoHandler {
+        socket: Option<Socket>,
+    }
+
+    #[async_trait]
+    impl ServerEventHandler for EchoHandler {
+        async fn on_connect(&mut self, socket: Socket) {
+            self.socket = Some(socket);
+        }
+
+        async fn on_disconnect(&mut self) {}
+
+        async fn on_message(&mut self, data: PacketData) {
+            if let Some(socket) = self.socket.as_mut() {
+                socket.emit(data).await;
+            }
+        }
+    }
+
+    async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(|| EchoHandler { socket: None });
+        task::spawn(server.serve(listener));
+
+        format!("http://{}/engine.io/", addr)
+    }
+
+    async fn assert_echo(version: ProtocolVersion, base64: bool) {
+        let url = echo_server().await;
+        let (handler, events) = RecordingHandler::new();
+        let mut messages = testing::messages(events);
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Polling])
+            .protocol_version(version)
+            .force_base64(base64)
+            .connect(handler)
+            .await
+            .unwrap();
+        client.emit_str("hello".into()).await.unwrap();
+        client.emit_bytes(vec![1, 2, 3]).await.unwrap();
+
+        assert_eq!(messages.next().await, Some(PacketData::Str("hello".into())));
+        assert_eq!(
+            messages.next().await,
+            Some(PacketData::Bytes(vec![1, 2, 3]))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_client_echo_v3() {
+        assert_echo(ProtocolVersion::V3, false).await;
+    }
+
+    #[async_std::test]
+    async fn test_client_echo_v3_base64() {
+        assert_echo(ProtocolVersion::V3, true).await;
+    }
+
+    #[async_std::test]
+    async fn test_client_echo_v4() {
+        assert_echo(ProtocolVersion::V4, false).await;
+    }
+
+    /// Records the messages of a session, followed by `None` once it is closed.
+    struct SessionRecorder {
+        events: mpsc::UnboundedSender<Option<PacketData>>,
+    }
+
+    #[async_trait]
+    impl ServerEventHandler for SessionRecorder {
+        async fn on_connect(&mut self, _socket: Socket) {}
+
+        async fn on_disconnect(&mut self) {
+            self.events.unbounded_send(None).unwrap();
+        }
+
+        async fn on_message(&mut self, data: PacketData) {
+            self.events.unbounded_send(Some(data)).unwrap();
+        }
+    }
+
+    #[async_std::test]
+    async fn test_client_close_ends_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/engine.io/", listener.local_addr().unwrap());
+        let (events_sender, mut events) = mpsc::unbounded();
+        let server = Server::new(move || SessionRecorder {
+            events: events_sender.clone(),
+        });
+        task::spawn(server.serve(listener));
+
+        let (handler, _events) = RecordingHandler::new();
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Polling])
+            .protocol_version(ProtocolVersion::V4)
+            .connect(handler)
+            .await
+            .unwrap();
+        client.emit_str("bye".into()).await.unwrap();
+        client.close().await.unwrap();
+        client.join().await.unwrap();
+
+        assert_eq!(
+            events.next().await,
+            Some(Some(PacketData::Str("bye".into())))
+        );
+        assert_eq!(events.next().await, Some(None));
+    }
+
+    #[async_std::test]
+    async fn test_text_encoded_client_payloads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/engine.io/", listener.local_addr().unwrap());
+        let (events_sender, mut events) = mpsc::unbounded();
+        let server = Server::new(move || SessionRecorder {
+            events: events_sender.clone(),
+        });
+        task::spawn(server.serve(listener));
+
+        let (handler, _events) = RecordingHandler::new();
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Polling])
+            .payload_encoding(PayloadEncoding::Text)
+            .connect(handler)
+            .await
+            .unwrap();
+        client.emit_str("héllo 😀".into()).await.unwrap();
+        client.emit_bytes(vec![1, 2, 3]).await.unwrap();
+        client.close().await.unwrap();
+        client.join().await.unwrap();
+
+        assert_eq!(
+            events.next().await,
+            Some(Some(PacketData::Str("héllo 😀".into())))
+        );
+        assert_eq!(
+            events.next().await,
+            Some(Some(PacketData::Bytes(vec![1, 2, 3])))
+        );
+        assert_eq!(events.next().await, Some(None));
+    }
+
+    fn request(method: Method, query: &str) -> Request {
+        let url = format!("http://localhost/engine.io/?{}", query);
+        Request::new(method, url.as_str())
+    }
+
+    #[async_std::test]
+    async fn test_handshake_and_errors() {
+        let server = Server::new(|| EchoHandler { socket: None });
+
+        let mut response = server
+            .handle(request(Method::Get, "EIO=4&transport=polling"))
+            .await;
+        assert_eq!(response.status(), StatusCode::Ok);
+        let body = response.body_string().await.unwrap();
+        assert!(body.starts_with(r#"0{"sid":""#));
+        assert!(body.contains(r#""maxPayload":1000000"#));
+
+        let mut response = server
+            .handle(request(Method::Get, "EIO=4&transport=polling&sid=unknown"))
+            .await;
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert_eq!(
+            response.body_string().await.unwrap(),
+            r#"{"code":1,"message":"Session ID unknown"}"#
+        );
+
+        let response = server
+            .handle(request(Method::Get, "EIO=2&transport=polling"))
+            .await;
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        let response = server
+            .handle(request(Method::Get, "EIO=4&transport=flash"))
+            .await;
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        let response = server
+            .handle(request(Method::Post, "EIO=4&transport=polling"))
+            .await;
+        assert_eq!(response.status(), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn test_base64_payloads() {
+        let server = Server::new(|| EchoHandler { socket: None });
+
+        let mut response = server
+            .handle(request(Method::Get, "EIO=3&transport=polling"))
+            .await;
+        assert_eq!(response.body_bytes().await.unwrap()[0], 0);
+
+        let mut response = server
+            .handle(request(Method::Get, "EIO=3&transport=polling&b64=1"))
+            .await;
+        assert_eq!(response.content_type(), Some(http_types::mime::PLAIN));
+        let body = response.body_string().await.unwrap();
+        let (length, open) = body.split_once(':').unwrap();
+        assert_eq!(length.parse::<usize>().unwrap(), open.chars().count());
+        assert!(open.starts_with(r#"0{"sid":""#));
+    }
+
+    #[async_std::test]
+    async fn test_payload_limit() {
+        let server = Server::new(|| EchoHandler { socket: None });
+        let mut response = server
+            .handle(request(Method::Get, "EIO=4&transport=polling"))
+            .await;
+        let body = response.body_string().await.unwrap();
+        let open: OpenPacket = serde_json::from_str(&body[1..]).unwrap();
+        let query = format!("EIO=4&transport=polling&sid={}", open.sid);
+        let post = |body: Body| {
+            let mut request = request(Method::Post, &query);
+            request.set_body(body);
+            server.handle(request)
+        };
+
+        let limit = MAX_PAYLOAD as usize;
+        let response = post(Body::from_string(format!("4{}", "a".repeat(limit - 1)))).await;
+        assert_eq!(response.status(), StatusCode::Ok);
+
+        let too_large = format!("4{}", "a".repeat(limit));
+        let response = post(Body::from_string(too_large.clone())).await;
+        assert_eq!(response.status(), StatusCode::PayloadTooLarge);
+
+        // Without a known length, the body is read up to the limit
+        let reader = io::Cursor::new(too_large.into_bytes());
+        let response = post(Body::from_reader(io::BufReader::new(reader), None)).await;
+        assert_eq!(response.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[async_std::test]
+    async fn test_session_times_out_without_heartbeat() {
+        let server = Server::new(|| EchoHandler { socket: None })
+            .ping_interval(Duration::from_millis(20))
+            .ping_timeout(Duration::from_millis(20));
+
+        let mut response = server
+            .handle(request(Method::Get, "EIO=4&transport=polling"))
+            .await;
+        let body = response.body_string().await.unwrap();
+        let open: OpenPacket = serde_json::from_str(&body[1..]).unwrap();
+        let query = format!("EIO=4&transport=polling&sid={}", open.sid);
+
+        // The server pings, but we never answer
+        let mut response = server.handle(request(Method::Get, &query)).await;
+        assert_eq!(response.body_string().await.unwrap(), "2");
+        let mut response = server.handle(request(Method::Get, &query)).await;
+        assert_eq!(response.body_string().await.unwrap(), "1");
+
+        let response = server.handle(request(Method::Get, &query)).await;
+        assert_eq!(response.status(), StatusCode::BadRequest);
+    }
+}
@@ -0,0 +1,167 @@
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// The data of a socket.io event, which is JSON that may contain byte buffers.
+///
+/// Byte buffers are sent as binary attachments of the event, e.g. the data
+/// `{"name":"image.png","content":<bytes>}` is sent as the JSON
+/// `{"name":"image.png","content":{"_placeholder":true,"num":0}}`,
+/// followed by the bytes as a binary message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Bytes(Vec<u8>),
+    Array(Vec<Data>),
+    Object(BTreeMap<String, Data>),
+}
+
+impl From<Value> for Data {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Data::Null,
+            Value::Bool(b) => Data::Bool(b),
+            Value::Number(n) => Data::Number(n),
+            Value::String(s) => Data::String(s),
+            Value::Array(values) => Data::Array(values.into_iter().map(Data::from).collect()),
+            Value::Object(map) => Data::Object(
+                map.into_iter()
+                    .map(|(key, value)| (key, Data::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Self {
+        Data::Bytes(bytes)
+    }
+}
+
+impl Data {
+    /// The data as JSON, unless it contains byte buffers.
+    pub fn into_json(self) -> Option<Value> {
+        Some(match self {
+            Data::Null => Value::Null,
+            Data::Bool(b) => Value::Bool(b),
+            Data::Number(n) => Value::Number(n),
+            Data::String(s) => Value::String(s),
+            Data::Bytes(_) => return None,
+            Data::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(Data::into_json)
+                    .collect::<Option<_>>()?,
+            ),
+            Data::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, data)| data.into_json().map(|value| (key, value)))
+                    .collect::<Option<Map<_, _>>>()?,
+            ),
+        })
+    }
+
+    /// The bytes, if the data is a byte buffer.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Data::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Replace the byte buffers by placeholders, which refer
+    /// to the position of the buffers moved to `attachments`.
+    pub(crate) fn deconstruct(self, attachments: &mut Vec<Vec<u8>>) -> Value {
+        match self {
+            Data::Bytes(bytes) => {
+                attachments.push(bytes);
+                serde_json::json!({ "_placeholder": true, "num": attachments.len() - 1 })
+            }
+            Data::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|data| data.deconstruct(attachments))
+                    .collect(),
+            ),
+            Data::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, data)| (key, data.deconstruct(attachments)))
+                    .collect(),
+            ),
+            // Contains no byte buffers
+            json => json.into_json().unwrap_or(Value::Null),
+        }
+    }
+
+    /// Replace the placeholders in `value` by the `attachments` they refer to.
+    /// Returns `None` if a placeholder refers to a missing attachment.
+    pub(crate) fn reconstruct(value: Value, attachments: &[Vec<u8>]) -> Option<Data> {
+        match value {
+            Value::Object(map) if map.get("_placeholder") == Some(&Value::Bool(true)) => {
+                let num = map.get("num")?.as_u64()?;
+                let bytes = attachments.get(usize::try_from(num).ok()?)?;
+                Some(Data::Bytes(bytes.clone()))
+            }
+            Value::Array(values) => Some(Data::Array(
+                values
+                    .into_iter()
+                    .map(|value| Data::reconstruct(value, attachments))
+                    .collect::<Option<_>>()?,
+            )),
+            Value::Object(map) => Some(Data::Object(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        Data::reconstruct(value, attachments).map(|data| (key, data))
+                    })
+                    .collect::<Option<_>>()?,
+            )),
+            json => Some(Data::from(json)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn file() -> Data {
+        let mut map = BTreeMap::new();
+        map.insert("name".to_owned(), Data::String("image.png".into()));
+        map.insert("content".to_owned(), Data::Bytes(vec![1, 2, 3]));
+        Data::Array(vec![Data::Object(map), Data::Bytes(vec![4])])
+    }
+
+    #[test]
+    fn test_deconstruction_and_reconstruction() {
+        let mut attachments = Vec::new();
+        let value = file().deconstruct(&mut attachments);
+
+        assert_eq!(
+            value,
+            json!([
+                { "name": "image.png", "content": { "_placeholder": true, "num": 0 } },
+                { "_placeholder": true, "num": 1 }
+            ])
+        );
+        assert_eq!(attachments, vec![vec![1, 2, 3], vec![4]]);
+        assert_eq!(Data::reconstruct(value, &attachments), Some(file()));
+    }
+
+    #[test]
+    fn test_missing_attachment_is_rejected() {
+        let value = json!([{ "_placeholder": true, "num": 1 }]);
+        assert_eq!(Data::reconstruct(value, &[vec![1]]), None);
+    }
+
+    #[test]
+    fn test_json_conversion() {
+        let value = json!({ "list": [1, "two", null, true] });
+        assert_eq!(Data::from(value.clone()).into_json(), Some(value));
+        assert_eq!(file().into_json(), None);
+    }
+}
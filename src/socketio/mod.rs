@@ -0,0 +1,9 @@
+//! A socket.io client on top of the engine.io [`Client`](../struct.Client.html),
+//! which exchanges named events with JSON data.
+
+mod data;
+mod packet;
+mod socket;
+
+pub use data::Data;
+pub use socket::{AckError, Socket, SocketBuilder};
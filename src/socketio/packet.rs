@@ -0,0 +1,243 @@
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// The type of a socket.io packet, sent as its first character.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PacketType {
+    Connect,
+    Disconnect,
+    Event,
+    Ack,
+    /// An error of the server, `CONNECT_ERROR` in protocol version 5.
+    Error,
+    BinaryEvent,
+    BinaryAck,
+}
+
+impl PacketType {
+    fn from_char(c: char) -> Option<Self> {
+        use PacketType::*;
+        match c {
+            '0' => Some(Connect),
+            '1' => Some(Disconnect),
+            '2' => Some(Event),
+            '3' => Some(Ack),
+            '4' => Some(Error),
+            '5' => Some(BinaryEvent),
+            '6' => Some(BinaryAck),
+            _ => None,
+        }
+    }
+
+    /// Whether the packet is followed by binary attachments.
+    pub(crate) fn is_binary(self) -> bool {
+        self == PacketType::BinaryEvent || self == PacketType::BinaryAck
+    }
+
+    fn to_char(self) -> char {
+        use PacketType::*;
+        match self {
+            Connect => '0',
+            Disconnect => '1',
+            Event => '2',
+            Ack => '3',
+            Error => '4',
+            BinaryEvent => '5',
+            BinaryAck => '6',
+        }
+    }
+}
+
+/// A socket.io packet, which is carried as the text of an engine.io message,
+/// e.g. `2/chat,12["message","hello"]` for an event in the `/chat` namespace
+/// that the server should acknowledge with id 12.
+///
+/// Binary packets state the number of binary engine.io messages that follow
+/// them, e.g. `51-["upload",{"_placeholder":true,"num":0}]`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Packet {
+    pub(crate) packet_type: PacketType,
+    pub(crate) attachments: usize,
+    pub(crate) namespace: String,
+    pub(crate) id: Option<u64>,
+    pub(crate) data: Option<Value>,
+}
+
+impl Packet {
+    pub(crate) fn new(packet_type: PacketType, data: Option<Value>) -> Self {
+        Packet {
+            packet_type,
+            attachments: 0,
+            namespace: "/".into(),
+            id: None,
+            data,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        let mut encoded = self.packet_type.to_char().to_string();
+        if self.packet_type.is_binary() {
+            encoded.push_str(&self.attachments.to_string());
+            encoded.push('-');
+        }
+        if self.namespace != "/" {
+            encoded.push_str(&self.namespace);
+            encoded.push(',');
+        }
+        if let Some(id) = self.id {
+            encoded.push_str(&id.to_string());
+        }
+        if let Some(data) = &self.data {
+            encoded.push_str(&data.to_string());
+        }
+        encoded
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PacketDecodeError {
+    description: String,
+}
+
+impl PacketDecodeError {
+    fn new(description: &str) -> Self {
+        PacketDecodeError {
+            description: description.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for PacketDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl FromStr for Packet {
+    type Err = PacketDecodeError;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut chars = string.chars();
+        let packet_type = chars
+            .next()
+            .and_then(PacketType::from_char)
+            .ok_or_else(|| PacketDecodeError::new("Invalid packet type"))?;
+        let mut rest = chars.as_str();
+
+        let mut attachments = 0;
+        if packet_type.is_binary() {
+            let end = rest
+                .find('-')
+                .ok_or_else(|| PacketDecodeError::new("Missing attachment count"))?;
+            attachments = rest[..end]
+                .parse()
+                .map_err(|_| PacketDecodeError::new("Invalid attachment count"))?;
+            rest = &rest[end + 1..];
+        }
+
+        let mut namespace = "/";
+        if rest.starts_with('/') {
+            let end = rest.find(',').unwrap_or(rest.len());
+            namespace = &rest[..end];
+            rest = rest.get(end + 1..).unwrap_or("");
+        }
+
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let id = match digits {
+            0 => None,
+            _ => Some(
+                rest[..digits]
+                    .parse()
+                    .map_err(|_| PacketDecodeError::new("Invalid packet id"))?,
+            ),
+        };
+        rest = &rest[digits..];
+
+        let data = match rest {
+            "" => None,
+            json => Some(
+                serde_json::from_str(json)
+                    .map_err(|_| PacketDecodeError::new("Invalid packet data"))?,
+            ),
+        };
+
+        Ok(Packet {
+            packet_type,
+            attachments,
+            namespace: namespace.to_owned(),
+            id,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_event_encoding() {
+        let packet = Packet::new(
+            PacketType::Event,
+            Some(json!(["message", { "text": "hello" }])),
+        );
+        assert_eq!(packet.encode(), r#"2["message",{"text":"hello"}]"#);
+
+        let packet = Packet {
+            namespace: "/chat".into(),
+            id: Some(12),
+            ..Packet::new(PacketType::Event, Some(json!(["message", "hello"])))
+        };
+        assert_eq!(packet.encode(), r#"2/chat,12["message","hello"]"#);
+    }
+
+    #[test]
+    fn test_packet_decoding() {
+        let packet: Packet = "0".parse().unwrap();
+        assert_eq!(packet, Packet::new(PacketType::Connect, None));
+
+        let packet: Packet = r#"0{"sid":"abc"}"#.parse().unwrap();
+        assert_eq!(packet.data, Some(json!({ "sid": "abc" })));
+
+        let packet: Packet = r#"3/chat,12["ok"]"#.parse().unwrap();
+        assert_eq!(packet.packet_type, PacketType::Ack);
+        assert_eq!(packet.namespace, "/chat");
+        assert_eq!(packet.id, Some(12));
+        assert_eq!(packet.data, Some(json!(["ok"])));
+
+        let packet: Packet = "1/admin".parse().unwrap();
+        assert_eq!(packet.packet_type, PacketType::Disconnect);
+        assert_eq!(packet.namespace, "/admin");
+        assert_eq!(packet.data, None);
+    }
+
+    #[test]
+    fn test_binary_packets() {
+        let placeholder = json!({ "_placeholder": true, "num": 0 });
+        let packet = Packet {
+            attachments: 1,
+            namespace: "/files".into(),
+            ..Packet::new(
+                PacketType::BinaryEvent,
+                Some(json!(["upload", placeholder])),
+            )
+        };
+        let encoded = r#"51-/files,["upload",{"_placeholder":true,"num":0}]"#;
+
+        assert_eq!(packet.encode(), encoded);
+        assert_eq!(encoded.parse::<Packet>().unwrap(), packet);
+    }
+
+    #[test]
+    fn test_invalid_packets_are_rejected() {
+        assert!("".parse::<Packet>().is_err());
+        assert!("9".parse::<Packet>().is_err());
+        assert!("2[\"message\"".parse::<Packet>().is_err());
+        assert!("299999999999999999999999[]".parse::<Packet>().is_err());
+        assert!("51[]".parse::<Packet>().is_err());
+        assert!("5x-[]".parse::<Packet>().is_err());
+    }
+}
@@ -0,0 +1,866 @@
+use super::data::Data;
+use super::packet::{Packet, PacketType};
+use crate::builder::{ClientBuilder, ProtocolVersion};
+use crate::client::{Client, DisconnectReason, EventHandler, Sender};
+use crate::error::EIOError;
+use crate::packet::PacketData;
+use async_std::future;
+use async_std::sync::Mutex as AsyncMutex;
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::channel::oneshot;
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error};
+
+type Handler = Arc<dyn Fn(Data) + Send + Sync>;
+
+/// The state of a socket in a single namespace, shared by
+/// the socket and the handler of its connection.
+struct Namespace {
+    handlers: Mutex<FnvHashMap<String, Handler>>,
+    /// The emits waiting for an acknowledgement, by packet id.
+    acks: Mutex<FnvHashMap<u64, oneshot::Sender<Data>>>,
+    /// Whether the server accepted the connection to the namespace.
+    connected: AtomicBool,
+    /// The packets emitted while not connected, with their attachments,
+    /// which are sent once the server accepts the connection.
+    send_buffer: Mutex<Vec<(Packet, Vec<Vec<u8>>)>>,
+}
+
+/// The state of an engine.io connection, which carries the sockets
+/// of all namespaces the client connected to.
+struct Shared {
+    version: ProtocolVersion,
+    /// Locked while sending a packet and its attachments,
+    /// which must not be interleaved with other packets.
+    sender: AsyncMutex<Sender>,
+    /// The most packets buffered per namespace until it is connected,
+    /// the queue capacity of the client.
+    buffer_capacity: usize,
+    namespaces: Mutex<Namespaces>,
+}
+
+struct Namespaces {
+    /// Whether an engine.io session is open, over which
+    /// new namespaces can be connected right away.
+    session_open: bool,
+    by_name: FnvHashMap<String, Arc<Namespace>>,
+}
+
+impl Shared {
+    /// Send a packet, followed by its binary attachments.
+    async fn send(&self, packet: Packet, attachments: Vec<Vec<u8>>) -> Result<(), EIOError> {
+        let mut sender = self.sender.lock().await;
+        write(&mut sender, packet, attachments).await
+    }
+
+    /// Send a packet of `namespace` like [`send`](#method.send) if the server
+    /// accepted the connection to it, otherwise buffer it until it does.
+    async fn send_connected(
+        &self,
+        namespace: &Namespace,
+        packet: Packet,
+        attachments: Vec<Vec<u8>>,
+    ) -> Result<(), EIOError> {
+        let mut sender = self.sender.lock().await;
+        if namespace.connected.load(Ordering::SeqCst) {
+            write(&mut sender, packet, attachments).await
+        } else if sender.is_closed() {
+            Err(EIOError::Closed)
+        } else {
+            let mut send_buffer = namespace.send_buffer.lock().unwrap();
+            if send_buffer.len() >= self.buffer_capacity {
+                return Err(EIOError::QueueFull);
+            }
+            debug!("Buffering packet until connected to {}", packet.namespace);
+            send_buffer.push((packet, attachments));
+            Ok(())
+        }
+    }
+
+    /// Mark `namespace` as connected and send the packets buffered until then.
+    /// Both happen under the lock of the sender, so that no packet is buffered
+    /// after the buffer was flushed.
+    async fn flush(&self, namespace: &Namespace) -> Result<(), EIOError> {
+        let mut sender = self.sender.lock().await;
+        namespace.connected.store(true, Ordering::SeqCst);
+        let buffered = std::mem::take(&mut *namespace.send_buffer.lock().unwrap());
+        for (packet, attachments) in buffered {
+            write(&mut sender, packet, attachments).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a packet, followed by its binary attachments.
+async fn write(
+    sender: &mut Sender,
+    packet: Packet,
+    attachments: Vec<Vec<u8>>,
+) -> Result<(), EIOError> {
+    sender.emit_str(packet.encode()).await?;
+    for attachment in attachments {
+        sender.emit_bytes(attachment).await?;
+    }
+    Ok(())
+}
+
+/// The reason an acknowledgement was not received.
+#[derive(Debug, PartialEq)]
+pub enum AckError {
+    /// The server did not acknowledge the event in time.
+    Timeout,
+    /// The connection was closed before the server acknowledged the event.
+    Disconnected,
+}
+
+impl std::error::Error for AckError {}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::Timeout => write!(f, "Acknowledgement timed out"),
+            AckError::Disconnected => write!(f, "Disconnected before acknowledgement"),
+        }
+    }
+}
+
+/// A builder to register event handlers before a [`Socket`](struct.Socket.html)
+/// connects, so that no event is missed, like the `connect` event.
+///
+/// ```no_run
+/// # use engineio::socketio::SocketBuilder;
+/// # async fn connect() -> Result<(), engineio::EIOError> {
+/// let mut socket = SocketBuilder::new("http://localhost:3000")
+///     .on("connect", |_| println!("Connected"))
+///     .on("chat", |data| println!("Received {:?}", data))
+///     .connect()
+///     .await?;
+/// socket.emit("chat", serde_json::json!("hello")).await?;
+///
+/// // Another namespace over the same connection
+/// let mut admin = socket
+///     .of("/admin")
+///     .on("stats", |data| println!("Stats {:?}", data))
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SocketBuilder {
+    connection: Connection,
+    namespace: String,
+    handlers: FnvHashMap<String, Handler>,
+}
+
+/// The connection a socket is built for.
+enum Connection {
+    New(Box<ClientBuilder>),
+    Shared(Arc<Shared>),
+}
+
+impl SocketBuilder {
+    /// Create a builder for a socket to the server at `url`.
+    /// Unless a path is configured, the path of `url` is used,
+    /// which defaults to `/socket.io/`.
+    pub fn new(url: &str) -> Self {
+        ClientBuilder::new(url).default_path("/socket.io/").into()
+    }
+
+    /// The namespace to connect to, by default `/`.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_owned();
+        self
+    }
+
+    /// Call `handler` with the data of every `event` the server emits.
+    ///
+    /// The events `connect`, `disconnect` and `error` are emitted by
+    /// the socket itself, when the server accepted the connection,
+    /// when the connection is closed and when the server sends an error.
+    pub fn on(mut self, event: &str, handler: impl Fn(Data) + Send + Sync + 'static) -> Self {
+        self.handlers.insert(event.to_owned(), Arc::new(handler));
+        self
+    }
+
+    /// Connect to the namespace with the registered handlers.
+    pub async fn connect(self) -> Result<Socket, EIOError> {
+        let namespace = Arc::new(Namespace {
+            handlers: Mutex::new(self.handlers),
+            acks: Mutex::new(FnvHashMap::default()),
+            connected: AtomicBool::new(false),
+            send_buffer: Mutex::new(Vec::new()),
+        });
+
+        let (shared, client) = match self.connection {
+            Connection::New(builder) => {
+                let options = (*builder).build()?;
+                let version = options.protocol_version;
+                let buffer_capacity = options.queue_capacity;
+                let mut by_name = FnvHashMap::default();
+                by_name.insert(self.namespace.clone(), Arc::clone(&namespace));
+
+                let mut shared = None;
+                let socket_handler = |sender| {
+                    let state = Arc::new(Shared {
+                        version,
+                        sender: AsyncMutex::new(sender),
+                        buffer_capacity,
+                        namespaces: Mutex::new(Namespaces {
+                            session_open: false,
+                            by_name,
+                        }),
+                    });
+                    shared = Some(Arc::clone(&state));
+                    SocketHandler {
+                        shared: state,
+                        pending: None,
+                    }
+                };
+                let client = Client::open_with(options, socket_handler).await?;
+                let shared = shared.expect("handler is created when the client is opened");
+                (shared, Some(client))
+            }
+            Connection::Shared(shared) => {
+                let session_open = {
+                    let mut namespaces = shared.namespaces.lock().unwrap();
+                    namespaces
+                        .by_name
+                        .insert(self.namespace.clone(), Arc::clone(&namespace));
+                    namespaces.session_open
+                };
+                // Otherwise the handler connects once the session is open
+                if session_open {
+                    shared.send(connect_packet(&self.namespace), vec![]).await?;
+                }
+                (shared, None)
+            }
+        };
+
+        Ok(Socket {
+            namespace: self.namespace,
+            state: namespace,
+            shared,
+            client,
+            next_id: 0,
+        })
+    }
+}
+
+/// Use the engine.io options of a `ClientBuilder` for the socket,
+/// e.g. to select the transports or the protocol version.
+impl From<ClientBuilder> for SocketBuilder {
+    fn from(client: ClientBuilder) -> Self {
+        SocketBuilder {
+            connection: Connection::New(Box::new(client)),
+            namespace: "/".into(),
+            handlers: FnvHashMap::default(),
+        }
+    }
+}
+
+fn connect_packet(namespace: &str) -> Packet {
+    Packet {
+        namespace: namespace.to_owned(),
+        ..Packet::new(PacketType::Connect, None)
+    }
+}
+
+/// A socket.io client, which exchanges named events with JSON data
+/// over an engine.io [`Client`](../struct.Client.html).
+///
+/// Servers speaking version 3 of the engine.io protocol use version 4 of
+/// the socket.io protocol (socket.io 2), engine.io version 4 servers use
+/// socket.io version 5 (socket.io 3 and later).
+///
+/// Each socket belongs to a single namespace. The sockets of further
+/// namespaces, created with [`of`](#method.of), share the connection.
+pub struct Socket {
+    namespace: String,
+    state: Arc<Namespace>,
+    shared: Arc<Shared>,
+    /// The connection, which is owned by the socket that opened it.
+    client: Option<Client>,
+    /// The id of the next event that asks for an acknowledgement.
+    next_id: u64,
+}
+
+impl Socket {
+    /// Connect to the socket.io server at `url`.
+    /// Use a [`SocketBuilder`](struct.SocketBuilder.html) to receive the `connect` event.
+    pub async fn connect(url: &str) -> Result<Socket, EIOError> {
+        SocketBuilder::new(url).connect().await
+    }
+
+    /// The namespace of the socket.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Create a builder for a socket of another namespace,
+    /// which shares the connection of this socket.
+    pub fn of(&self, namespace: &str) -> SocketBuilder {
+        SocketBuilder {
+            connection: Connection::Shared(Arc::clone(&self.shared)),
+            namespace: namespace.to_owned(),
+            handlers: FnvHashMap::default(),
+        }
+    }
+
+    /// Call `handler` with the data of every `event` the server emits from now on,
+    /// replacing the previous handler of that event.
+    pub fn on(&self, event: &str, handler: impl Fn(Data) + Send + Sync + 'static) {
+        self.state
+            .handlers
+            .lock()
+            .unwrap()
+            .insert(event.to_owned(), Arc::new(handler));
+    }
+
+    /// Emit `event` with `data` to the server.
+    /// Byte buffers in `data` are sent as binary attachments.
+    ///
+    /// Until the server accepted the connection to the namespace, e.g. right
+    /// after connecting or while reconnecting, the event is buffered and sent
+    /// once it did. Fails with `EIOError::Closed` once the connection is closed,
+    /// or with `EIOError::QueueFull` if as many events as the queue capacity
+    /// of the client are buffered already.
+    pub async fn emit(&mut self, event: &str, data: impl Into<Data>) -> Result<(), EIOError> {
+        let (packet, attachments) = self.event_packet(event, data.into(), None);
+        self.shared
+            .send_connected(&self.state, packet, attachments)
+            .await
+    }
+
+    /// Emit `event` with `data` to the server like [`emit`](#method.emit), and wait
+    /// for the server to acknowledge it, which resolves to the data of the acknowledgement.
+    ///
+    /// Fails if no acknowledgement arrives within `timeout`,
+    /// or the connection is closed before.
+    pub async fn emit_with_ack(
+        &mut self,
+        event: &str,
+        data: impl Into<Data>,
+        timeout: Duration,
+    ) -> Result<Data, AckError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (sender, receiver) = oneshot::channel();
+        self.state.acks.lock().unwrap().insert(id, sender);
+        let (packet, attachments) = self.event_packet(event, data.into(), Some(id));
+        if self
+            .shared
+            .send_connected(&self.state, packet, attachments)
+            .await
+            .is_err()
+        {
+            self.state.acks.lock().unwrap().remove(&id);
+            return Err(AckError::Disconnected);
+        }
+
+        match future::timeout(timeout, receiver).await {
+            Ok(Ok(data)) => Ok(data),
+            Ok(Err(oneshot::Canceled)) => Err(AckError::Disconnected),
+            Err(_) => {
+                self.state.acks.lock().unwrap().remove(&id);
+                Err(AckError::Timeout)
+            }
+        }
+    }
+
+    /// An event packet of this namespace, which is a binary event
+    /// if `data` contains byte buffers, with its attachments.
+    fn event_packet(&self, event: &str, data: Data, id: Option<u64>) -> (Packet, Vec<Vec<u8>>) {
+        let mut attachments = Vec::new();
+        let args = Data::Array(vec![Data::String(event.to_owned()), data]);
+        let args = args.deconstruct(&mut attachments);
+
+        let packet_type = match attachments.len() {
+            0 => PacketType::Event,
+            _ => PacketType::BinaryEvent,
+        };
+        let packet = Packet {
+            attachments: attachments.len(),
+            namespace: self.namespace.clone(),
+            id,
+            ..Packet::new(packet_type, Some(args))
+        };
+        (packet, attachments)
+    }
+
+    /// Leave the namespace. The connection stays open for the sockets
+    /// of other namespaces.
+    pub async fn disconnect(&mut self) {
+        self.shared
+            .namespaces
+            .lock()
+            .unwrap()
+            .by_name
+            .remove(&self.namespace);
+        let packet = Packet {
+            namespace: self.namespace.clone(),
+            ..Packet::new(PacketType::Disconnect, None)
+        };
+        // Nothing to tell the server if the connection is closed already
+        let _ = self.shared.send(packet, vec![]).await;
+        self.state.send_buffer.lock().unwrap().clear();
+        self.state.disconnect();
+    }
+
+    /// Wait until the connection is closed. Only the socket that opened
+    /// the connection can wait for it, for sockets created with
+    /// [`of`](#method.of) this returns right away.
+    pub async fn join(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.client.as_mut() {
+            Some(client) => client.join().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Namespace {
+    fn dispatch(&self, event: &str, data: Data) {
+        // The lock is released before calling the handler, which may register handlers itself
+        let handler = self.handlers.lock().unwrap().get(event).cloned();
+        match handler {
+            Some(handler) => handler(data),
+            None => debug!("No handler for event {}", event),
+        }
+    }
+
+    /// Fail all emits that wait for an acknowledgement,
+    /// and emit the `disconnect` event if we were connected.
+    fn disconnect(&self) {
+        self.acks.lock().unwrap().clear();
+        if self.connected.swap(false, Ordering::SeqCst) {
+            self.dispatch("disconnect", Data::Null);
+        }
+    }
+
+    /// Handle a packet, whose placeholders refer to `attachments`.
+    fn handle_packet(&self, packet: Packet, attachments: &[Vec<u8>]) {
+        let data = match packet.data.clone() {
+            Some(value) => match Data::reconstruct(value, attachments) {
+                Some(data) => Some(data),
+                None => {
+                    error!("Missing attachment of packet {:?}", packet);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        match packet.packet_type {
+            PacketType::Connect => {
+                self.connected.store(true, Ordering::SeqCst);
+                self.dispatch("connect", data.unwrap_or(Data::Null));
+            }
+            PacketType::Disconnect => self.disconnect(),
+            PacketType::Event | PacketType::BinaryEvent => match data {
+                Some(Data::Array(mut args)) if !args.is_empty() => {
+                    let event = args.remove(0);
+                    let data = args.into_iter().next().unwrap_or(Data::Null);
+                    match event {
+                        Data::String(event) => self.dispatch(&event, data),
+                        event => error!("Invalid event name {:?}", event),
+                    }
+                }
+                data => error!("Invalid event data {:?}", data),
+            },
+            PacketType::Ack | PacketType::BinaryAck => {
+                let sender = packet
+                    .id
+                    .and_then(|id| self.acks.lock().unwrap().remove(&id));
+                let data = match data {
+                    Some(Data::Array(args)) => args.into_iter().next().unwrap_or(Data::Null),
+                    _ => Data::Null,
+                };
+                match sender {
+                    // The emit may have timed out in the meantime
+                    Some(sender) => {
+                        let _ = sender.send(data);
+                    }
+                    None => debug!("Unexpected acknowledgement {:?}", packet.id),
+                }
+            }
+            PacketType::Error => {
+                self.dispatch("error", data.unwrap_or(Data::Null));
+            }
+        }
+    }
+}
+
+/// Translates the engine.io messages of a connection into
+/// the socket.io events of its namespaces.
+struct SocketHandler {
+    shared: Arc<Shared>,
+    /// A binary packet that waits for its attachments.
+    pending: Option<(Packet, Vec<Vec<u8>>)>,
+}
+
+impl SocketHandler {
+    async fn route(&self, packet: Packet, attachments: &[Vec<u8>]) {
+        let namespace = self
+            .shared
+            .namespaces
+            .lock()
+            .unwrap()
+            .by_name
+            .get(&packet.namespace)
+            .cloned();
+        match namespace {
+            Some(namespace) => {
+                if packet.packet_type == PacketType::Connect {
+                    // A failed write closes the connection, which is reported by `join`
+                    let _ = self.shared.flush(&namespace).await;
+                }
+                namespace.handle_packet(packet, attachments)
+            }
+            None => debug!("Packet for unknown namespace {}", packet.namespace),
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for SocketHandler {
+    async fn on_connect(&mut self) {
+        let namespaces: Vec<String> = {
+            let mut namespaces = self.shared.namespaces.lock().unwrap();
+            namespaces.session_open = true;
+            namespaces.by_name.keys().cloned().collect()
+        };
+
+        for namespace in namespaces {
+            // In version 3 the server connects us to the default namespace by itself
+            if self.shared.version == ProtocolVersion::V3 && namespace == "/" {
+                continue;
+            }
+            // A failed write closes the connection, which is reported by `join`
+            let _ = self.shared.send(connect_packet(&namespace), vec![]).await;
+        }
+    }
+
+    async fn on_disconnect(&mut self, _reason: DisconnectReason) {
+        self.pending = None;
+        let namespaces: Vec<Arc<Namespace>> = {
+            let mut namespaces = self.shared.namespaces.lock().unwrap();
+            namespaces.session_open = false;
+            namespaces.by_name.values().cloned().collect()
+        };
+
+        for namespace in namespaces {
+            namespace.disconnect();
+        }
+    }
+
+    async fn on_message(&mut self, data: PacketData) {
+        match data {
+            PacketData::Str(text) => {
+                if self.pending.take().is_some() {
+                    error!("Binary packet is missing attachments");
+                }
+                match text.parse::<Packet>() {
+                    Ok(packet) if packet.attachments > 0 => {
+                        self.pending = Some((packet, Vec::new()));
+                    }
+                    Ok(packet) => self.route(packet, &[]).await,
+                    Err(err) => error!("Could not decode packet {}: {}", text, err),
+                }
+            }
+            PacketData::Bytes(bytes) => match self.pending.take() {
+                Some((packet, mut attachments)) => {
+                    attachments.push(bytes);
+                    if attachments.len() == packet.attachments {
+                        self.route(packet, &attachments).await;
+                    } else {
+                        self.pending = Some((packet, attachments));
+                    }
+                }
+                None => error!("Unexpected binary message"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::Transport;
+    use crate::testing::{self, ws_server, OPEN};
+    use async_tungstenite::tungstenite::Message;
+    use futures::channel::mpsc;
+    use futures::stream::StreamExt;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+
+    /// Accepts all namespaces and echoes all events, including binary attachments.
+    fn echo(message: Message) -> Option<Vec<Message>> {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Binary(bytes) => return Some(vec![Message::Binary(bytes)]),
+            _ => return Some(vec![]),
+        };
+        let reply = match text.as_str() {
+            "40" => r#"40{"sid":"def"}"#.to_owned(),
+            text if text.starts_with("40/") || text.starts_with("42") || text.starts_with("45") => {
+                text.to_owned()
+            }
+            _ => return Some(vec![]),
+        };
+        Some(vec![Message::text(reply)])
+    }
+
+    /// Starts a stand-in socket.io server over a websocket, which accepts
+    /// the connection like a server of the given version and echoes.
+    async fn echo_server(version: ProtocolVersion) -> String {
+        match version {
+            // The server connects the client to the default namespace by itself
+            ProtocolVersion::V3 => ws_server(&[OPEN, "40"], echo).await,
+            ProtocolVersion::V4 => ws_server(&[OPEN], echo).await,
+        }
+    }
+
+    async fn assert_echo(version: ProtocolVersion) {
+        let url = echo_server(version).await;
+        let (sender, mut events) = mpsc::unbounded();
+        let connected = sender.clone();
+
+        let mut socket = SocketBuilder::from(
+            ClientBuilder::new(&url)
+                .transports(&[Transport::Websocket])
+                .protocol_version(version),
+        )
+        .on("connect", move |data| {
+            connected.unbounded_send(("connect", data)).unwrap();
+        })
+        .on("chat", move |data| {
+            sender.unbounded_send(("chat", data)).unwrap();
+        })
+        .connect()
+        .await
+        .unwrap();
+
+        let (event, _) = events.next().await.unwrap();
+        assert_eq!(event, "connect");
+
+        socket
+            .emit("chat", json!({ "text": "hello" }))
+            .await
+            .unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(("chat", Data::from(json!({ "text": "hello" }))))
+        );
+    }
+
+    fn polling_url(builder: SocketBuilder) -> String {
+        match builder.connection {
+            Connection::New(builder) => builder.build().unwrap().url(Transport::Polling, None),
+            Connection::Shared(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_path_of_url_is_kept() {
+        let url = polling_url(SocketBuilder::new("http://localhost:8080"));
+        assert!(url.starts_with("http://localhost:8080/socket.io/?"));
+
+        let url = polling_url(SocketBuilder::new("http://localhost:8080/chat/"));
+        assert!(url.starts_with("http://localhost:8080/chat/?"));
+    }
+
+    #[async_std::test]
+    async fn test_socket_echo_v3() {
+        assert_echo(ProtocolVersion::V3).await;
+    }
+
+    #[async_std::test]
+    async fn test_socket_echo_v4() {
+        assert_echo(ProtocolVersion::V4).await;
+    }
+
+    /// Acknowledges `echo` events with their data, ignores `ignore` events
+    /// and closes the connection on `close` events.
+    fn acknowledge(message: Message) -> Option<Vec<Message>> {
+        let packet: Packet = match message.to_text().ok()?.strip_prefix('4').map(str::parse) {
+            Some(Ok(packet)) => packet,
+            _ => return Some(vec![]),
+        };
+        let args = match packet.data {
+            Some(Value::Array(args)) => args,
+            _ => return Some(vec![]),
+        };
+        match args[0].as_str() {
+            Some("echo") => {
+                let ack = Packet {
+                    id: packet.id,
+                    ..Packet::new(PacketType::Ack, Some(Value::Array(args[1..].to_vec())))
+                };
+                Some(vec![Message::text(format!("4{}", ack.encode()))])
+            }
+            Some("close") => None,
+            _ => Some(vec![]),
+        }
+    }
+
+    /// Starts a stand-in socket.io server over a websocket that acknowledges events.
+    async fn ack_server() -> String {
+        ws_server(&[OPEN, "40"], acknowledge).await
+    }
+
+    #[async_std::test]
+    async fn test_emit_with_ack() {
+        let url = ack_server().await;
+        let mut socket =
+            SocketBuilder::from(ClientBuilder::new(&url).transports(&[Transport::Websocket]))
+                .connect()
+                .await
+                .unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let ack = socket.emit_with_ack("echo", json!([1, 2]), timeout).await;
+        assert_eq!(ack, Ok(Data::from(json!([1, 2]))));
+        let ack = socket.emit_with_ack("echo", json!("again"), timeout).await;
+        assert_eq!(ack, Ok(Data::from(json!("again"))));
+
+        let ack = socket
+            .emit_with_ack("ignore", Value::Null, Duration::from_millis(50))
+            .await;
+        assert_eq!(ack, Err(AckError::Timeout));
+        assert!(socket.state.acks.lock().unwrap().is_empty());
+
+        let ack = socket.emit_with_ack("close", Value::Null, timeout).await;
+        assert_eq!(ack, Err(AckError::Disconnected));
+    }
+
+    #[async_std::test]
+    async fn test_emit_right_after_connect() {
+        // Accept the connection only after a ping round trip,
+        // and drop clients that emit before it was accepted
+        let mut connected = false;
+        let url = ws_server(&[OPEN], move |message| match message.to_text() {
+            Ok("40") => Some(vec![Message::text("2")]),
+            Ok("3") => {
+                connected = true;
+                Some(vec![Message::text(r#"40{"sid":"def"}"#)])
+            }
+            Ok(_) if connected => acknowledge(message),
+            _ => None,
+        })
+        .await;
+        let builder = ClientBuilder::new(&url)
+            .transports(&[Transport::Websocket])
+            .protocol_version(ProtocolVersion::V4);
+        let mut socket = SocketBuilder::from(builder).connect().await.unwrap();
+
+        socket.emit("first", json!(1)).await.unwrap();
+        let ack = socket
+            .emit_with_ack("echo", json!(2), Duration::from_secs(5))
+            .await;
+
+        assert_eq!(ack, Ok(Data::from(json!(2))));
+        assert!(socket.state.send_buffer.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_send_buffer_is_bounded() {
+        // Never accepts the connection to the namespace
+        let url = ws_server(&[OPEN], testing::ignore).await;
+        let builder = ClientBuilder::new(&url)
+            .transports(&[Transport::Websocket])
+            .queue_capacity(2);
+        let mut socket = SocketBuilder::from(builder).connect().await.unwrap();
+
+        socket.emit("first", json!(1)).await.unwrap();
+        socket.emit("second", json!(2)).await.unwrap();
+        let result = socket.emit("third", json!(3)).await;
+        assert_eq!(result, Err(EIOError::QueueFull));
+        assert_eq!(socket.state.send_buffer.lock().unwrap().len(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_namespaces_share_connection() {
+        let url = echo_server(ProtocolVersion::V3).await;
+        let (sender, mut events) = mpsc::unbounded();
+        let (root_sender, chat_sender) = (sender.clone(), sender.clone());
+        let (connected, disconnected) = (sender.clone(), sender);
+
+        let mut root =
+            SocketBuilder::from(ClientBuilder::new(&url).transports(&[Transport::Websocket]))
+                .on("chat", move |data| {
+                    root_sender.unbounded_send(("/", data)).unwrap();
+                })
+                .connect()
+                .await
+                .unwrap();
+        let mut chat = root
+            .of("/chat")
+            .on("connect", move |_| {
+                connected.unbounded_send(("connect", Data::Null)).unwrap();
+            })
+            .on("chat", move |data| {
+                chat_sender.unbounded_send(("/chat", data)).unwrap();
+            })
+            .on("disconnect", move |_| {
+                disconnected
+                    .unbounded_send(("disconnect", Data::Null))
+                    .unwrap();
+            })
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(chat.namespace(), "/chat");
+        assert_eq!(events.next().await, Some(("connect", Data::Null)));
+
+        chat.emit("chat", json!("hello chat")).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(("/chat", Data::from(json!("hello chat"))))
+        );
+        root.emit("chat", json!("hello root")).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(("/", Data::from(json!("hello root"))))
+        );
+
+        chat.disconnect().await;
+        assert_eq!(events.next().await, Some(("disconnect", Data::Null)));
+        root.emit("chat", json!("still here")).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(("/", Data::from(json!("still here"))))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_binary_attachments() {
+        let url = echo_server(ProtocolVersion::V3).await;
+        let (sender, mut events) = mpsc::unbounded();
+
+        let mut socket =
+            SocketBuilder::from(ClientBuilder::new(&url).transports(&[Transport::Websocket]))
+                .on("upload", move |data| sender.unbounded_send(data).unwrap())
+                .connect()
+                .await
+                .unwrap();
+
+        let mut file = BTreeMap::new();
+        file.insert("name".to_owned(), Data::String("image.png".into()));
+        file.insert("content".to_owned(), Data::Bytes(vec![1, 2, 3]));
+        file.insert("thumbnail".to_owned(), Data::Bytes(vec![4, 5]));
+        socket
+            .emit("upload", Data::Object(file.clone()))
+            .await
+            .unwrap();
+        socket.emit("upload", vec![6]).await.unwrap();
+
+        assert_eq!(events.next().await, Some(Data::Object(file)));
+        assert_eq!(events.next().await, Some(Data::Bytes(vec![6])));
+    }
+}
@@ -0,0 +1,156 @@
+//! Stand-in servers and handlers shared by the tests.
+
+use crate::client::{DisconnectReason, EventHandler};
+use crate::packet::PacketData;
+use async_std::io::prelude::{ReadExt, WriteExt};
+use async_std::net::TcpListener;
+use async_std::task;
+use async_trait::async_trait;
+use async_tungstenite::tungstenite::Message;
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+
+/// The open packet of a session that is not upgraded, with the default heartbeat.
+pub(crate) const OPEN: &str =
+    r#"0{"sid":"abc","upgrades":[],"pingInterval":25000,"pingTimeout":5000}"#;
+
+/// Starts a stand-in server that accepts websocket connections under the
+/// returned url. Every connection is sent the `greeting` messages, usually
+/// starting with the open packet. Each message received afterwards is
+/// answered with the messages `on_message` returns, or the connection is
+/// closed if it returns `None`.
+pub(crate) async fn ws_server<F>(greeting: &'static [&'static str], on_message: F) -> String
+where
+    F: FnMut(Message) -> Option<Vec<Message>> + Clone + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut on_message = on_message.clone();
+            task::spawn(async move {
+                let mut ws = match async_tungstenite::accept_async(stream).await {
+                    Ok(ws) => ws,
+                    Err(_) => return,
+                };
+                for message in greeting {
+                    ws.send(Message::text(*message)).await.unwrap();
+                }
+                while let Some(Ok(message)) = ws.next().await {
+                    match on_message(message) {
+                        Some(replies) => {
+                            for reply in replies {
+                                // The client may be gone already
+                                let _ = ws.send(reply).await;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+            });
+        }
+    });
+
+    format!("http://{}/engine.io/", addr)
+}
+
+/// Answers engine.io pings with pongs and echoes all other messages.
+pub(crate) fn echo(message: Message) -> Option<Vec<Message>> {
+    let reply = match message {
+        Message::Text(text) if text.starts_with('2') => Message::text(format!("3{}", &text[1..])),
+        other => other,
+    };
+    Some(vec![reply])
+}
+
+/// Ignores all messages.
+pub(crate) fn ignore(_message: Message) -> Option<Vec<Message>> {
+    Some(vec![])
+}
+
+/// Starts a stand-in HTTP server that answers every request
+/// with the given `status` and `body`.
+pub(crate) async fn http_server(status: &'static str, body: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    task::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await;
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    format!("http://{}/engine.io/", addr)
+}
+
+/// An event of a [`RecordingHandler`](struct.RecordingHandler.html).
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    Connect,
+    Disconnect(DisconnectReason),
+    Reconnecting(u32),
+    Reconnected,
+    Message(PacketData),
+}
+
+/// Records the events of a client.
+pub(crate) struct RecordingHandler {
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl RecordingHandler {
+    /// A handler, and the stream of the events it records.
+    pub(crate) fn new() -> (Self, mpsc::UnboundedReceiver<Event>) {
+        let (events, receiver) = mpsc::unbounded();
+        (RecordingHandler { events }, receiver)
+    }
+}
+
+/// Only the messages of recorded `events`.
+pub(crate) fn messages(
+    events: impl Stream<Item = Event> + Unpin,
+) -> impl Stream<Item = PacketData> + Unpin {
+    events.filter_map(|event| {
+        future::ready(match event {
+            Event::Message(data) => Some(data),
+            _ => None,
+        })
+    })
+}
+
+#[async_trait]
+impl EventHandler for RecordingHandler {
+    async fn on_connect(&mut self) {
+        self.events.unbounded_send(Event::Connect).unwrap();
+    }
+
+    async fn on_disconnect(&mut self, reason: DisconnectReason) {
+        self.events
+            .unbounded_send(Event::Disconnect(reason))
+            .unwrap();
+    }
+
+    async fn on_message(&mut self, data: PacketData) {
+        self.events.unbounded_send(Event::Message(data)).unwrap();
+    }
+
+    async fn on_reconnecting(&mut self, attempt: u32) {
+        self.events
+            .unbounded_send(Event::Reconnecting(attempt))
+            .unwrap();
+    }
+
+    async fn on_reconnected(&mut self) {
+        self.events.unbounded_send(Event::Reconnected).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use crate::builder::ProtocolVersion;
+use crate::error::EIOError;
+use crate::packet::{Packet, PacketData, PacketType};
+use async_tungstenite::async_std::{connect_async, ConnectStream};
+use async_tungstenite::tungstenite::client::IntoClientRequest;
+use async_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use async_tungstenite::tungstenite::{Error, Message};
+use async_tungstenite::WebSocketStream;
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub(crate) type WebSocket = WebSocketStream<ConnectStream>;
+
+/// Open a websocket connection to the given engine.io url, sending the
+/// extra `headers` with the handshake request.
+/// An `http(s)` scheme is translated into its `ws(s)` counterpart.
+pub(crate) async fn connect(
+    url: &str,
+    headers: &[(HeaderName, HeaderValue)],
+) -> Result<WebSocket, EIOError> {
+    let mut request = to_ws_url(url).into_client_request()?;
+    for (name, value) in headers.iter() {
+        request.headers_mut().insert(name.clone(), value.clone());
+    }
+
+    let (stream, _response) = connect_async(request).await.map_err(|err| match err {
+        Error::Http(response) => {
+            let body = response.body().as_deref().unwrap_or_default();
+            EIOError::from_handshake_response(response.status().as_u16(), body)
+        }
+        Error::Io(err) => EIOError::Unreachable(Arc::new(err)),
+        err => EIOError::from(err),
+    })?;
+    Ok(stream)
+}
+
+fn to_ws_url(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else {
+        url.to_owned()
+    }
+}
+
+/// Frame a packet as a websocket message.
+/// String packets are sent as text messages, binary packets as binary
+/// messages. In version 3 their first byte is the packet type, e.g. `[4, ...data]`,
+/// while version 4 only sends the data of binary messages.
+pub(crate) fn encode(packet: &Packet, version: ProtocolVersion) -> Message {
+    match packet.data() {
+        PacketData::Str(_) => Message::text(packet.encode()),
+        PacketData::Bytes(bytes) if version == ProtocolVersion::V4 => {
+            Message::binary(bytes.clone())
+        }
+        PacketData::Bytes(_) => Message::binary(packet.encode_bytes()),
+    }
+}
+
+/// Decode a websocket message into a packet.
+/// Control frames carry no engine.io packet, in which case `None` is returned.
+pub(crate) fn decode(
+    message: Message,
+    version: ProtocolVersion,
+) -> Result<Option<Packet>, EIOError> {
+    let packet = match message {
+        Message::Text(text) => Packet::from_str(text.as_str()),
+        Message::Binary(bytes) if version == ProtocolVersion::V4 => {
+            Ok(Packet::with_bytes(PacketType::Message, bytes.to_vec()))
+        }
+        Message::Binary(bytes) => Packet::from_bytes(&bytes),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(packet?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::Transport;
+    use crate::client::Client;
+    use crate::packet::PacketType;
+    use crate::testing::{self, messages, RecordingHandler, OPEN};
+    use futures::stream::StreamExt;
+
+    #[test]
+    fn test_ws_url_translation() {
+        assert_eq!(
+            to_ws_url("http://localhost/engine.io/"),
+            "ws://localhost/engine.io/"
+        );
+        assert_eq!(
+            to_ws_url("https://localhost/engine.io/"),
+            "wss://localhost/engine.io/"
+        );
+        assert_eq!(
+            to_ws_url("ws://localhost/engine.io/"),
+            "ws://localhost/engine.io/"
+        );
+    }
+
+    #[test]
+    fn test_ws_framing() {
+        let v3 = ProtocolVersion::V3;
+        let text = Packet::with_str(PacketType::Message, "hello");
+        assert_eq!(encode(&text, v3), Message::text("4hello"));
+        assert_eq!(decode(encode(&text, v3), v3).unwrap(), Some(text));
+
+        let binary = Packet::with_bytes(PacketType::Message, vec![1, 2, 3]);
+        assert_eq!(encode(&binary, v3), Message::binary(vec![4, 1, 2, 3]));
+        assert_eq!(decode(encode(&binary, v3), v3).unwrap(), Some(binary));
+    }
+
+    #[test]
+    fn test_ws_framing_v4() {
+        let v4 = ProtocolVersion::V4;
+        let text = Packet::with_str(PacketType::Message, "hello");
+        assert_eq!(encode(&text, v4), Message::text("4hello"));
+
+        let binary = Packet::with_bytes(PacketType::Message, vec![1, 2, 3]);
+        assert_eq!(encode(&binary, v4), Message::binary(vec![1, 2, 3]));
+        assert_eq!(decode(encode(&binary, v4), v4).unwrap(), Some(binary));
+    }
+
+    #[async_std::test]
+    async fn test_client_over_websocket() {
+        let url = testing::ws_server(&[OPEN], testing::echo).await;
+        let (handler, events) = RecordingHandler::new();
+        let mut messages = messages(events);
+
+        let mut client = Client::builder(&url)
+            .transports(&[Transport::Websocket])
+            .connect(handler)
+            .await
+            .unwrap();
+        client.emit_str("hello".into()).await.unwrap();
+        client.emit_bytes(vec![1, 2, 3]).await.unwrap();
+
+        assert_eq!(messages.next().await, Some(PacketData::Str("hello".into())));
+        assert_eq!(
+            messages.next().await,
+            Some(PacketData::Bytes(vec![1, 2, 3]))
+        );
+    }
+}